@@ -0,0 +1,507 @@
+use crate::vars::Vars;
+
+/// Evaluate an integer arithmetic expression with C operators, as used by
+/// `$((...))`, `((...))`, `for ((...))` and `let`. Names stand for
+/// variables; unset or empty ones are 0.
+pub fn eval(expr: &str, vars: &mut Vars) -> Result<i64, String> {
+    eval_nested(expr, 0, vars)
+}
+
+/// Variables may hold expressions themselves; `depth` stops a variable
+/// that refers to itself.
+fn eval_nested(expr: &str, depth: usize, vars: &mut Vars) -> Result<i64, String> {
+    if depth > 16 {
+        return Err(format!("{}: expression recursion level exceeded", expr.trim()));
+    }
+    let tokens = tokenize(expr)?;
+    if tokens.is_empty() {
+        return Ok(0);
+    }
+    let mut parser = Parser { source: expr, tokens, pos: 0 };
+    let ast = parser.comma()?;
+    if let Some(tok) = parser.tokens.get(parser.pos) {
+        return Err(format!("{}: syntax error in expression (error token is \"{}\")", expr.trim(), tok));
+    }
+    Evaluator { source: expr, depth, vars }.eval(&ast)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Num(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+impl std::fmt::Display for Tok {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Tok::Num(n) => write!(f, "{}", n),
+            Tok::Name(name) => f.write_str(name),
+            Tok::Op(op) => f.write_str(op),
+        }
+    }
+}
+
+/// Operators, longest first so that the lexer takes the longest match.
+const OPERATORS: &[&str] = &[
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=", "*=",
+    "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~", "?",
+    ":", "=", ",", "(", ")",
+];
+
+fn tokenize(expr: &str) -> Result<Vec<Tok>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = expr;
+    loop {
+        rest = rest.trim_start();
+        let Some(c) = rest.chars().next() else { break };
+        if c.is_ascii_digit() {
+            let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '#' && c != '_').unwrap_or(rest.len());
+            tokens.push(Tok::Num(number(&rest[..len], expr)?));
+            rest = &rest[len..];
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '$' {
+            // `$name` and `${name}` mean the same as `name`.
+            let body = rest.strip_prefix('$').unwrap_or(rest);
+            let (name, len) = match body.strip_prefix('{') {
+                Some(inner) => {
+                    let end = inner.find('}').ok_or_else(|| format!("{}: missing `}}`", expr.trim()))?;
+                    (&inner[..end], end + 2)
+                }
+                None => {
+                    let end = body.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(body.len());
+                    (&body[..end], end)
+                }
+            };
+            if name.is_empty() {
+                return Err(format!("{}: syntax error: operand expected (error token is \"{}\")", expr.trim(), rest));
+            }
+            tokens.push(Tok::Name(name.to_string()));
+            rest = &body[len..];
+        } else {
+            let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) else {
+                return Err(format!("{}: syntax error: invalid arithmetic operator (error token is \"{}\")", expr.trim(), rest));
+            };
+            tokens.push(Tok::Op(op));
+            rest = &rest[op.len()..];
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parse an integer constant: decimal, `0x` hex, leading-`0` octal, or
+/// `base#digits` for bases 2 to 64.
+fn number(text: &str, expr: &str) -> Result<i64, String> {
+    let invalid = || format!("{}: value too great for base (error token is \"{}\")", expr.trim(), text);
+    let (base, digits) = if let Some((base, digits)) = text.split_once('#') {
+        match base.parse::<u32>() {
+            Ok(b) if (2..=64).contains(&b) => (b, digits),
+            _ => return Err(format!("{}: invalid arithmetic base (error token is \"{}\")", expr.trim(), text)),
+        }
+    } else if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        (16, hex)
+    } else if text.len() > 1 && text.starts_with('0') {
+        (8, &text[1..])
+    } else {
+        (10, text)
+    };
+    if digits.is_empty() {
+        return Err(invalid());
+    }
+    let mut value: i64 = 0;
+    for c in digits.chars() {
+        // Past base 36, lower and upper case letters are different digits.
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base > 36 => c as u32 - 'A' as u32 + 36,
+            'A'..='Z' => c as u32 - 'A' as u32 + 10,
+            '@' => 62,
+            '_' => 63,
+            _ => return Err(invalid()),
+        };
+        if digit >= base {
+            return Err(invalid());
+        }
+        value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+    }
+    Ok(value)
+}
+
+#[derive(Debug)]
+enum Expr {
+    Num(i64),
+    Var(String),
+    Unary(&'static str, Box<Expr>),
+    /// `++x` / `--x` (`post` false) or `x++` / `x--`.
+    Step { name: String, delta: i64, post: bool },
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+    /// `name = value`, or `name op= value` with the binary operator.
+    Assign(String, Option<&'static str>, Box<Expr>),
+}
+
+/// Binary operators from lowest to highest precedence.
+const LEVELS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Tok>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Tok::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn error(&self) -> String {
+        match self.tokens.get(self.pos) {
+            Some(tok) => format!("{}: syntax error in expression (error token is \"{}\")", self.source.trim(), tok),
+            None => format!("{}: syntax error: operand expected", self.source.trim()),
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error())
+        }
+    }
+
+    fn comma(&mut self) -> Result<Expr, String> {
+        let mut expr = self.assignment()?;
+        while self.peek_op() == Some(",") {
+            self.pos += 1;
+            expr = Expr::Binary(",", Box::new(expr), Box::new(self.assignment()?));
+        }
+        Ok(expr)
+    }
+
+    fn assignment(&mut self) -> Result<Expr, String> {
+        if let (Some(Tok::Name(name)), Some(Tok::Op(op))) = (self.tokens.get(self.pos), self.tokens.get(self.pos + 1)) {
+            let op: &'static str = op;
+            if op.ends_with('=') && !matches!(op, "==" | "!=" | "<=" | ">=") {
+                let name = name.clone();
+                self.pos += 2;
+                let value = self.assignment()?;
+                let binary = op.strip_suffix('=').filter(|b| !b.is_empty());
+                let binary = binary.map(|b| OPERATORS.iter().find(|o| **o == b).copied().unwrap_or("+"));
+                return Ok(Expr::Assign(name, binary, Box::new(value)));
+            }
+        }
+        self.conditional()
+    }
+
+    fn conditional(&mut self) -> Result<Expr, String> {
+        let condition = self.binary(0)?;
+        if self.peek_op() != Some("?") {
+            return Ok(condition);
+        }
+        self.pos += 1;
+        let then = self.comma()?;
+        self.expect(":")?;
+        let otherwise = self.assignment()?;
+        Ok(Expr::Cond(Box::new(condition), Box::new(then), Box::new(otherwise)))
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == LEVELS.len() {
+            return self.power();
+        }
+        let mut left = self.binary(level + 1)?;
+        while let Some(op) = self.peek_op().filter(|op| LEVELS[level].contains(op)) {
+            self.pos += 1;
+            let right = self.binary(level + 1)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    /// `**` is right-associative and binds tighter than `*`.
+    fn power(&mut self) -> Result<Expr, String> {
+        let base = self.unary()?;
+        if self.peek_op() == Some("**") {
+            self.pos += 1;
+            let exponent = self.power()?;
+            return Ok(Expr::Binary("**", Box::new(base), Box::new(exponent)));
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek_op() {
+            Some(op @ ("++" | "--")) => {
+                self.pos += 1;
+                match self.tokens.get(self.pos) {
+                    Some(Tok::Name(name)) => {
+                        let name = name.clone();
+                        self.pos += 1;
+                        Ok(Expr::Step { name, delta: if op == "++" { 1 } else { -1 }, post: false })
+                    }
+                    _ => Err(self.error()),
+                }
+            }
+            Some(op @ ("+" | "-" | "!" | "~")) => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            }
+            _ => self.postfix(),
+        }
+    }
+
+    fn postfix(&mut self) -> Result<Expr, String> {
+        match self.tokens.get(self.pos).cloned() {
+            Some(Tok::Num(n)) => {
+                self.pos += 1;
+                Ok(Expr::Num(n))
+            }
+            Some(Tok::Name(name)) => {
+                self.pos += 1;
+                match self.peek_op() {
+                    Some(op @ ("++" | "--")) => {
+                        self.pos += 1;
+                        Ok(Expr::Step { name, delta: if op == "++" { 1 } else { -1 }, post: true })
+                    }
+                    _ => Ok(Expr::Var(name)),
+                }
+            }
+            Some(Tok::Op("(")) => {
+                self.pos += 1;
+                let inner = self.comma()?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            _ => Err(self.error()),
+        }
+    }
+}
+
+struct Evaluator<'a> {
+    source: &'a str,
+    depth: usize,
+    vars: &'a mut Vars,
+}
+
+impl Evaluator<'_> {
+    fn eval(&mut self, expr: &Expr) -> Result<i64, String> {
+        match expr {
+            Expr::Num(n) => Ok(*n),
+            Expr::Var(name) => self.var(name),
+            Expr::Unary(op, operand) => {
+                let value = self.eval(operand)?;
+                Ok(match *op {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value,
+                })
+            }
+            Expr::Step { name, delta, post } => {
+                let old = self.var(name)?;
+                let new = old.wrapping_add(*delta);
+                self.set_var(name, new)?;
+                Ok(if *post { old } else { new })
+            }
+            Expr::Binary("&&", left, right) => {
+                Ok((self.eval(left)? != 0 && self.eval(right)? != 0) as i64)
+            }
+            Expr::Binary("||", left, right) => {
+                Ok((self.eval(left)? != 0 || self.eval(right)? != 0) as i64)
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                self.apply(op, left, right)
+            }
+            Expr::Cond(condition, then, otherwise) => {
+                if self.eval(condition)? != 0 { self.eval(then) } else { self.eval(otherwise) }
+            }
+            Expr::Assign(name, op, value) => {
+                let mut value = self.eval(value)?;
+                if let Some(op) = op {
+                    let current = self.var(name)?;
+                    value = self.apply(op, current, value)?;
+                }
+                self.set_var(name, value)?;
+                Ok(value)
+            }
+        }
+    }
+
+    fn apply(&self, op: &str, left: i64, right: i64) -> Result<i64, String> {
+        Ok(match op {
+            "," => right,
+            "|" => left | right,
+            "^" => left ^ right,
+            "&" => left & right,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "<" => (left < right) as i64,
+            "<=" => (left <= right) as i64,
+            ">" => (left > right) as i64,
+            ">=" => (left >= right) as i64,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => {
+                return Err(format!("{}: division by 0", self.source.trim()));
+            }
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "**" if right < 0 => {
+                return Err(format!("{}: exponent less than 0", self.source.trim()));
+            }
+            "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+            _ => unreachable!("unknown operator {}", op),
+        })
+    }
+
+    /// A variable's value; one that is not a number is evaluated as an
+    /// expression in turn.
+    fn var(&mut self, name: &str) -> Result<i64, String> {
+        let value = self.vars.get(name).unwrap_or_default().trim().to_string();
+        if value.is_empty() {
+            return Ok(0);
+        }
+        match value.parse() {
+            Ok(n) => Ok(n),
+            Err(_) => eval_nested(&value, self.depth + 1, self.vars),
+        }
+    }
+
+    fn set_var(&mut self, name: &str, value: i64) -> Result<(), String> {
+        self.vars.set(name, value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calc(expr: &str) -> i64 {
+        eval(expr, &mut Vars::default()).unwrap()
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(calc("1 + 2 * 3"), 7);
+        assert_eq!(calc("(1 + 2) * 3"), 9);
+        assert_eq!(calc("10 - 4 - 3"), 3);
+        assert_eq!(calc("1 << 2 + 1"), 8);
+        assert_eq!(calc("1 | 2 ^ 3 & 1"), 3);
+        assert_eq!(calc("1 < 2 == 1"), 1);
+        assert_eq!(calc("-3 % 2"), -1);
+        assert_eq!(calc("!0 + ~0"), 0);
+        assert_eq!(calc("0 || 2 && 3"), 1);
+        assert_eq!(calc(""), 0);
+    }
+
+    #[test]
+    fn power() {
+        assert_eq!(calc("2 ** 10"), 1024);
+        assert_eq!(calc("2 ** 3 ** 2"), 512);
+        assert_eq!(calc("-2 ** 2"), 4);
+        assert_eq!(calc("2 * 3 ** 2"), 18);
+        assert!(eval("2 ** -1", &mut Vars::default()).is_err());
+    }
+
+    #[test]
+    fn ternary_and_comma() {
+        assert_eq!(calc("1 ? 2 : 3"), 2);
+        assert_eq!(calc("0 ? 2 : 0 ? 3 : 4"), 4);
+        assert_eq!(calc("1, 2, 3"), 3);
+        let mut vars = Vars::default();
+        assert_eq!(eval("x = 1, y = x + 1, x + y", &mut vars).unwrap(), 3);
+        // Only the chosen branch is evaluated.
+        assert_eq!(eval("1 ? a = 5 : (b = 6)", &mut vars).unwrap(), 5);
+        assert_eq!(vars.get("a"), Some("5"));
+        assert_eq!(vars.get("b"), None);
+    }
+
+    #[test]
+    fn increment_and_decrement() {
+        let mut vars = Vars::default();
+        vars.set("i", "5").unwrap();
+        assert_eq!(eval("i++", &mut vars).unwrap(), 5);
+        assert_eq!(vars.get("i"), Some("6"));
+        assert_eq!(eval("++i", &mut vars).unwrap(), 7);
+        assert_eq!(eval("i--", &mut vars).unwrap(), 7);
+        assert_eq!(eval("--i", &mut vars).unwrap(), 5);
+        assert_eq!(eval("n++", &mut vars).unwrap(), 0);
+        assert_eq!(vars.get("n"), Some("1"));
+    }
+
+    #[test]
+    fn assignment_operators() {
+        let mut vars = Vars::default();
+        assert_eq!(eval("x = 10", &mut vars).unwrap(), 10);
+        assert_eq!(eval("x += 5", &mut vars).unwrap(), 15);
+        assert_eq!(eval("x -= 3", &mut vars).unwrap(), 12);
+        assert_eq!(eval("x *= 2", &mut vars).unwrap(), 24);
+        assert_eq!(eval("x /= 5", &mut vars).unwrap(), 4);
+        assert_eq!(eval("x %= 3", &mut vars).unwrap(), 1);
+        assert_eq!(eval("x <<= 4", &mut vars).unwrap(), 16);
+        assert_eq!(eval("x >>= 1", &mut vars).unwrap(), 8);
+        assert_eq!(eval("x |= 3", &mut vars).unwrap(), 11);
+        assert_eq!(eval("x &= 6", &mut vars).unwrap(), 2);
+        assert_eq!(eval("x ^= 7", &mut vars).unwrap(), 5);
+        assert_eq!(vars.get("x"), Some("5"));
+        assert_eq!(eval("a = b = 3", &mut vars).unwrap(), 3);
+        assert_eq!(vars.get("a"), Some("3"));
+    }
+
+    #[test]
+    fn variables() {
+        let mut vars = Vars::default();
+        vars.set("e", "2 + 3").unwrap();
+        vars.set("empty", "").unwrap();
+        assert_eq!(eval("e * 2", &mut vars).unwrap(), 10);
+        assert_eq!(eval("$e + ${e}", &mut vars).unwrap(), 10);
+        assert_eq!(eval("empty + unset", &mut vars).unwrap(), 0);
+        vars.set("r", "r").unwrap();
+        assert!(eval("r", &mut vars).is_err());
+    }
+
+    #[test]
+    fn bases() {
+        assert_eq!(calc("16#ff"), 255);
+        assert_eq!(calc("0xff"), 255);
+        assert_eq!(calc("0XFF"), 255);
+        assert_eq!(calc("017"), 15);
+        assert_eq!(calc("2#1010"), 10);
+        assert_eq!(calc("64#_"), 63);
+        assert!(eval("08", &mut Vars::default()).is_err());
+        assert!(eval("2#12", &mut Vars::default()).is_err());
+        assert!(eval("65#1", &mut Vars::default()).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let mut vars = Vars::default();
+        assert_eq!(eval("1 / 0", &mut vars).unwrap_err(), "1 / 0: division by 0");
+        assert_eq!(eval("5 % 0", &mut vars).unwrap_err(), "5 % 0: division by 0");
+        assert!(eval("x /= 0", &mut vars).is_err());
+        assert!(eval("1 +", &mut vars).is_err());
+        assert!(eval("(1", &mut vars).is_err());
+        assert!(eval("1 2", &mut vars).is_err());
+        assert!(eval("1 @ 2", &mut vars).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::lexer::Word;
+
+/// A sequence of and-or lists separated by `;` or newlines.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct List {
+    pub items: Vec<AndOr>,
+}
+
+/// Pipelines joined by `&&` and `||`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(AndOrOp, Pipeline)>,
+    /// Terminated by `&`: run without waiting for it.
+    pub background: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AndOrOp {
+    And, // &&
+    Or,  // ||
+}
+
+/// Commands connected by pipes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+    /// Preceded by `!`: the status is inverted.
+    pub negated: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Simple(SimpleCmd),
+    Compound(CompoundCmd, Vec<Redirect>),
+    /// `name() body` or `function name body`.
+    Function(FunctionDef),
+}
+
+/// A function definition. The body is shared with the shell's function
+/// table once the definition has run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDef {
+    pub name: String,
+    pub body: Rc<Command>,
+}
+
+/// Words and redirections, in the order they appeared.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimpleCmd {
+    pub assignments: Vec<Assignment>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+/// `name=value` before a command's words.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assignment {
+    pub name: String,
+    pub value: Word,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompoundCmd {
+    /// `{ list; }`, run in the current shell.
+    BraceGroup(List),
+    /// `( list )`, run in a forked copy of the shell.
+    Subshell(List),
+    /// `if list; then list; [elif list; then list;]... [else list;] fi`,
+    /// as (condition, body) branches.
+    If { branches: Vec<(List, List)>, otherwise: Option<List> },
+    /// `while list; do list; done`, or `until` if `until` is set.
+    While { until: bool, condition: List, body: List },
+    /// `for name [in words]; do list; done`. Without `in` the loop runs
+    /// over the positional parameters.
+    For { name: String, words: Option<Vec<Word>>, body: List },
+    /// `for ((init; condition; step)); do list; done`. The expressions
+    /// are expanded each time they are evaluated.
+    ArithFor { init: Word, condition: Word, step: Word, body: List },
+    /// `((expression))`: true if the expression is not zero.
+    Arith(Word),
+    /// `case word in pattern) list;; ... esac`.
+    Case { word: Word, items: Vec<CaseItem> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaseItem {
+    /// Alternatives separated by `|`.
+    pub patterns: Vec<Word>,
+    pub body: List,
+    pub terminator: CaseTerminator,
+}
+
+/// What happens after a `case` item's body has run.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaseTerminator {
+    Break,       // ;;
+    FallThrough, // ;& runs the next body without testing it
+    Continue,    // ;;& goes on testing the following patterns
+}
+
+/// One redirection. A command's redirections are applied in order, so
+/// `>out 2>&1` and `2>&1 >out` differ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    /// The descriptor written before the operator, as in `2>`.
+    pub fd: Option<i32>,
+    pub kind: RedirectKind,
+    /// A filename, for `<&` and `>&` a descriptor number or `-`, and for
+    /// here-documents the delimiter.
+    pub target: Word,
+    /// The body of a here-document.
+    pub here_doc: Option<Word>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectKind {
+    In,         // <
+    Out,        // >
+    Append,     // >>
+    Clobber,    // >|
+    ReadWrite,  // <>
+    DupIn,      // <&
+    DupOut,     // >&
+    OutErr,     // &>
+    AppendErr,  // &>>
+    HereDoc,    // <<
+    HereString, // <<<
+}
+
+impl Redirect {
+    /// The descriptor the redirection changes: the explicit one, or the
+    /// operator's default.
+    pub fn source_fd(&self) -> i32 {
+        self.fd.unwrap_or(match self.kind {
+            RedirectKind::In
+            | RedirectKind::ReadWrite
+            | RedirectKind::DupIn
+            | RedirectKind::HereDoc
+            | RedirectKind::HereString => 0,
+            _ => 1,
+        })
+    }
+}
+
+impl fmt::Display for List {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, and_or) in self.items.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}", and_or)?;
+            if and_or.background {
+                f.write_str(" &")?;
+            } else if i + 1 < self.items.len() {
+                f.write_str(";")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for AndOr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.first)?;
+        for (op, pipeline) in &self.rest {
+            let op = match op {
+                AndOrOp::And => "&&",
+                AndOrOp::Or => "||",
+            };
+            write!(f, " {} {}", op, pipeline)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.negated {
+            f.write_str("! ")?;
+        }
+        for (i, cmd) in self.commands.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" | ")?;
+            }
+            write!(f, "{}", cmd)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::Simple(simple) => write!(f, "{}", simple),
+            Command::Compound(compound, redirects) => {
+                write!(f, "{}", compound)?;
+                for redirect in redirects {
+                    write!(f, " {}", redirect)?;
+                }
+                Ok(())
+            }
+            Command::Function(def) => write!(f, "{} () {}", def.name, def.body),
+        }
+    }
+}
+
+/// A list followed by the `;` that ends it before a closing reserved
+/// word, unless it already ends with `&`.
+struct Terminated<'a>(&'a List);
+
+impl fmt::Display for Terminated<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)?;
+        match self.0.items.last() {
+            Some(last) if !last.background => f.write_str(";"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for CompoundCmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompoundCmd::BraceGroup(list) => write!(f, "{{ {} }}", Terminated(list)),
+            CompoundCmd::Subshell(list) => write!(f, "({})", list),
+            CompoundCmd::If { branches, otherwise } => {
+                for (i, (condition, body)) in branches.iter().enumerate() {
+                    let keyword = if i == 0 { "if" } else { " elif" };
+                    write!(f, "{} {} then {}", keyword, Terminated(condition), Terminated(body))?;
+                }
+                if let Some(body) = otherwise {
+                    write!(f, " else {}", Terminated(body))?;
+                }
+                f.write_str(" fi")
+            }
+            CompoundCmd::While { until, condition, body } => {
+                let keyword = if *until { "until" } else { "while" };
+                write!(f, "{} {} do {} done", keyword, Terminated(condition), Terminated(body))
+            }
+            CompoundCmd::For { name, words, body } => {
+                write!(f, "for {}", name)?;
+                if let Some(words) = words {
+                    f.write_str(" in")?;
+                    for word in words {
+                        write!(f, " {}", word)?;
+                    }
+                }
+                write!(f, "; do {} done", Terminated(body))
+            }
+            CompoundCmd::ArithFor { init, condition, step, body } => {
+                write!(f, "for (({}; {}; {})); do {} done", init, condition, step, Terminated(body))
+            }
+            CompoundCmd::Arith(expr) => write!(f, "(({}))", expr),
+            CompoundCmd::Case { word, items } => {
+                write!(f, "case {} in", word)?;
+                for item in items {
+                    let patterns: Vec<String> = item.patterns.iter().map(|p| p.to_string()).collect();
+                    let terminator = match item.terminator {
+                        CaseTerminator::Break => ";;",
+                        CaseTerminator::FallThrough => ";&",
+                        CaseTerminator::Continue => ";;&",
+                    };
+                    write!(f, " {}) {}{}", patterns.join("|"), item.body, terminator)?;
+                }
+                f.write_str(" esac")
+            }
+        }
+    }
+}
+
+impl fmt::Display for SimpleCmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let assignments = self.assignments.iter().map(|a| format!("{}={}", a.name, a.value));
+        let words = self.words.iter().map(|w| w.to_string());
+        let redirects = self.redirects.iter().map(|r| r.to_string());
+        let all: Vec<String> = assignments.chain(words).chain(redirects).collect();
+        f.write_str(&all.join(" "))
+    }
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.kind {
+            RedirectKind::In => "<",
+            RedirectKind::Out => ">",
+            RedirectKind::Append => ">>",
+            RedirectKind::Clobber => ">|",
+            RedirectKind::ReadWrite => "<>",
+            RedirectKind::DupIn => "<&",
+            RedirectKind::DupOut => ">&",
+            RedirectKind::OutErr => "&>",
+            RedirectKind::AppendErr => "&>>",
+            RedirectKind::HereDoc => "<<",
+            RedirectKind::HereString => "<<<",
+        };
+        if let Some(fd) = self.fd {
+            write!(f, "{}", fd)?;
+        }
+        write!(f, "{}{}", op, self.target)
+    }
+}
@@ -0,0 +1,234 @@
+use crate::lexer::{Word, WordPart};
+
+/// A piece of a word during brace expansion: a character of unquoted
+/// literal text, where braces and commas count, or any other part.
+#[derive(Debug, Clone, PartialEq)]
+enum Atom {
+    Char(char),
+    Part(WordPart),
+}
+
+/// Expand `{a,b}` lists and `{1..10..2}` sequences in a word, giving one
+/// word per combination. Braces that are quoted, unmatched or hold a
+/// single element are left alone.
+pub fn expand(word: &Word) -> Vec<Word> {
+    let atoms = word
+        .parts
+        .iter()
+        .flat_map(|part| match part {
+            WordPart::Literal(s) => s.chars().map(Atom::Char).collect(),
+            _ => vec![Atom::Part(part.clone())],
+        })
+        .collect::<Vec<_>>();
+    expand_atoms(&atoms).into_iter().map(|atoms| to_word(&atoms)).collect()
+}
+
+fn expand_atoms(atoms: &[Atom]) -> Vec<Vec<Atom>> {
+    let mut from = 0;
+    while let Some(open) = atoms[from..].iter().position(|a| *a == Atom::Char('{')).map(|i| from + i) {
+        let Some((close, commas)) = matching_brace(atoms, open) else {
+            from = open + 1;
+            continue;
+        };
+        let inner = &atoms[open + 1..close];
+        let alternatives: Vec<Vec<Atom>> = if commas.is_empty() {
+            match sequence(inner) {
+                Some(items) => items.into_iter().map(|s| s.chars().map(Atom::Char).collect()).collect(),
+                None => {
+                    from = open + 1;
+                    continue;
+                }
+            }
+        } else {
+            let mut bounds = vec![open];
+            bounds.extend(&commas);
+            bounds.push(close);
+            bounds.windows(2).map(|w| atoms[w[0] + 1..w[1]].to_vec()).collect()
+        };
+
+        let (prefix, suffix) = (&atoms[..open], &atoms[close + 1..]);
+        let mut words = Vec::new();
+        for alternative in alternatives {
+            let joined: Vec<Atom> = prefix.iter().chain(&alternative).chain(suffix).cloned().collect();
+            words.extend(expand_atoms(&joined));
+        }
+        return words;
+    }
+    vec![atoms.to_vec()]
+}
+
+/// The `}` closing the `{` at `open`, with the commas directly inside it.
+fn matching_brace(atoms: &[Atom], open: usize) -> Option<(usize, Vec<usize>)> {
+    let mut depth = 0;
+    let mut commas = Vec::new();
+    for (i, atom) in atoms.iter().enumerate().skip(open + 1) {
+        match atom {
+            Atom::Char('{') => depth += 1,
+            Atom::Char('}') if depth == 0 => return Some((i, commas)),
+            Atom::Char('}') => depth -= 1,
+            Atom::Char(',') if depth == 0 => commas.push(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The items of `start..end` or `start..end..step`, for integers or single
+/// letters. Integers written with leading zeros are padded to the same
+/// width.
+fn sequence(inner: &[Atom]) -> Option<Vec<String>> {
+    let text = inner
+        .iter()
+        .map(|a| match a {
+            Atom::Char(c) => Some(*c),
+            Atom::Part(_) => None,
+        })
+        .collect::<Option<String>>()?;
+    let fields: Vec<&str> = text.split("..").collect();
+    let (start, end, step) = match fields.as_slice() {
+        [start, end] => (*start, *end, 1),
+        [start, end, step] => (*start, *end, step.parse::<i64>().ok()?),
+        _ => return None,
+    };
+    let step = step.unsigned_abs().max(1) as usize;
+
+    if let (Ok(first), Ok(last)) = (start.parse::<i64>(), end.parse::<i64>()) {
+        let padded = |s: &str| s.trim_start_matches('-').len() > 1 && s.trim_start_matches('-').starts_with('0');
+        let width = if padded(start) || padded(end) { start.len().max(end.len()) } else { 0 };
+        let values: Vec<i64> = if first <= last {
+            (first..=last).step_by(step).collect()
+        } else {
+            (last..=first).rev().step_by(step).collect()
+        };
+        return Some(values.into_iter().map(|n| format_padded(n, width)).collect());
+    }
+
+    let (mut first, mut last) = (start.chars(), end.chars());
+    let (Some(first), None, Some(last), None) = (first.next(), first.next(), last.next(), last.next()) else {
+        return None;
+    };
+    if !first.is_ascii_alphabetic() || !last.is_ascii_alphabetic() {
+        return None;
+    }
+    let (first, last) = (first as u8, last as u8);
+    let values: Vec<u8> = if first <= last {
+        (first..=last).step_by(step).collect()
+    } else {
+        (last..=first).rev().step_by(step).collect()
+    };
+    Some(values.into_iter().map(|c| (c as char).to_string()).collect())
+}
+
+/// `n` with leading zeros up to `width` characters, counting a minus sign.
+fn format_padded(n: i64, width: usize) -> String {
+    if n < 0 {
+        format!("-{:0>1$}", n.unsigned_abs(), width.saturating_sub(1))
+    } else {
+        format!("{:0>1$}", n, width)
+    }
+}
+
+fn to_word(atoms: &[Atom]) -> Word {
+    let mut word = Word::default();
+    let mut lit = String::new();
+    for atom in atoms {
+        match atom {
+            Atom::Char(c) => lit.push(*c),
+            Atom::Part(part) => {
+                if !lit.is_empty() {
+                    word.parts.push(WordPart::Literal(std::mem::take(&mut lit)));
+                }
+                word.parts.push(part.clone());
+            }
+        }
+    }
+    if !lit.is_empty() {
+        word.parts.push(WordPart::Literal(lit));
+    }
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literal(s: &str) -> Word {
+        Word { parts: vec![WordPart::Literal(s.to_string())] }
+    }
+
+    fn texts(word: &Word) -> Vec<String> {
+        expand(word)
+            .iter()
+            .map(|w| {
+                w.parts
+                    .iter()
+                    .map(|part| match part {
+                        WordPart::Literal(s) => s.clone(),
+                        WordPart::Quoted(s) => format!("'{}'", s),
+                        other => format!("{:?}", other),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn brace(s: &str) -> Vec<String> {
+        texts(&literal(s))
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(brace("a{b,c,d}e"), ["abe", "ace", "ade"]);
+        assert_eq!(brace("{a,}"), ["a", ""]);
+        assert_eq!(brace("x{,y}"), ["x", "xy"]);
+        assert_eq!(brace("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+    }
+
+    #[test]
+    fn nesting() {
+        assert_eq!(brace("{a,b{1,2},c}"), ["a", "b1", "b2", "c"]);
+        assert_eq!(brace("{x{a..c},y}"), ["xa", "xb", "xc", "y"]);
+    }
+
+    #[test]
+    fn sequences() {
+        assert_eq!(brace("{1..4}"), ["1", "2", "3", "4"]);
+        assert_eq!(brace("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(brace("{1..10..2}"), ["1", "3", "5", "7", "9"]);
+        assert_eq!(brace("{10..1..-3}"), ["10", "7", "4", "1"]);
+        assert_eq!(brace("{-1..1}"), ["-1", "0", "1"]);
+    }
+
+    #[test]
+    fn zero_padding() {
+        assert_eq!(brace("{01..03}"), ["01", "02", "03"]);
+        assert_eq!(brace("{01..10..3}"), ["01", "04", "07", "10"]);
+        assert_eq!(brace("{8..010}"), ["008", "009", "010"]);
+        assert_eq!(brace("{-01..1}"), ["-01", "000", "001"]);
+    }
+
+    #[test]
+    fn letters() {
+        assert_eq!(brace("{a..e}"), ["a", "b", "c", "d", "e"]);
+        assert_eq!(brace("{e..a..2}"), ["e", "c", "a"]);
+        assert_eq!(brace("{a..1}"), ["{a..1}"]);
+    }
+
+    #[test]
+    fn left_alone() {
+        for word in ["{a}", "{}", "{a,b", "a,b}", "{1..}", "{a..bb}", "{1..2..x}", "}{"] {
+            assert_eq!(brace(word), [word]);
+        }
+        // Quoted braces and commas do not count.
+        let word = Word { parts: vec![WordPart::Quoted("{a,b}".to_string())] };
+        assert_eq!(texts(&word), ["'{a,b}'"]);
+        let word = Word {
+            parts: vec![
+                WordPart::Literal("{a".to_string()),
+                WordPart::Quoted(",".to_string()),
+                WordPart::Literal("b}".to_string()),
+            ],
+        };
+        assert_eq!(texts(&word), ["{a','b}"]);
+    }
+}
@@ -1,166 +1,912 @@
 use std::env;
-use std::io::{self, Write};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 
-#[cfg(windows)]
-const PATH_SEP: char = ';';
-#[cfg(not(windows))]
 const PATH_SEP: char = ':';
 
-fn home_dir() -> Option<PathBuf> {
-    env::var_os("HOME")
-        .or_else(|| env::var_os("USERPROFILE"))
-        .map(PathBuf::from)
+fn home_dir(shell: &Shell) -> Option<PathBuf> {
+    shell.vars.get("HOME").map(PathBuf::from)
 }
 
-use crate::shell::Shell;
+use crate::arith;
+use crate::jobs::{self, JobState};
+use crate::parser;
+use crate::script;
+use crate::shell::{Flow, Options, Shell, Shopts};
+use crate::sys::{self, Pid};
+use crate::vars::Var;
 
-#[cfg(not(windows))]
 pub const BUILTINS: &[&str] = &[
-    "cd", "clear", "echo", "env", "exit", "export", "pwd", "type", "unset", "which",
-];
-#[cfg(windows)]
-pub const BUILTINS: &[&str] = &[
-    "cd", "clear", "echo", "env", "exit", "export", "ls", "pwd", "type", "unset", "which",
+    ".", "alias", "bg", "break", "cd", "clear", "command", "continue", "declare", "disown", "echo", "env",
+    "exit", "export", "fg", "jobs", "kill", "let", "local", "pwd", "read", "readonly", "return", "set",
+    "shift", "shopt", "source", "type", "typeset", "unalias", "unset", "wait", "which",
 ];
 
 pub fn is_builtin(name: &str) -> bool {
     BUILTINS.contains(&name)
 }
 
-/// Returns the exit code. A value of -1 signals the shell to exit.
-pub fn run(argv: &[String], shell: &mut Shell) -> i32 {
+/// The standard streams a builtin reads and writes, with the command's
+/// redirections and pipes already in place.
+pub struct Io<'a> {
+    pub stdin: &'a mut dyn Read,
+    pub stdout: &'a mut dyn Write,
+    pub stderr: &'a mut dyn Write,
+}
+
+/// Returns the exit code. `exit` and `return` also set `shell.flow` so the
+/// executor stops running the rest of the input or function.
+pub fn run(argv: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
     match argv.first().map(String::as_str) {
         Some("exit") => {
-            let code = argv.get(1).and_then(|s| s.parse().ok()).unwrap_or(0);
+            let code = match argv.get(1).map(|n| n.parse::<i32>()) {
+                None => shell.last_exit_code,
+                Some(Ok(n)) => n & 0xff,
+                Some(Err(_)) => {
+                    writeln!(io.stderr, "exit: {}: numeric argument required", argv[1]).ok();
+                    2
+                }
+            };
             // Save history is handled in main before we exit, so just signal exit.
-            // We use a sentinel that the caller understands.
-            shell.last_exit_code = code;
-            -1 // sentinel: exit shell
+            shell.flow = Some(Flow::Exit(code));
+            code
         }
-        Some("echo") => {
-            println!("{}", argv[1..].join(" "));
+        Some(name @ ("break" | "continue")) => {
+            let count = match argv.get(1).map(|n| n.parse::<usize>()) {
+                None => 1,
+                Some(Ok(n)) if n > 0 => n,
+                Some(_) => {
+                    writeln!(io.stderr, "{}: {}: loop count out of range", name, argv[1]).ok();
+                    return 1;
+                }
+            };
+            if shell.loop_depth == 0 {
+                writeln!(io.stderr, "{}: only meaningful in a `for', `while', or `until' loop", name).ok();
+                return 0;
+            }
+            let count = count.min(shell.loop_depth);
+            shell.flow = Some(if name == "break" { Flow::Break(count) } else { Flow::Continue(count) });
             0
         }
+        Some("return") => {
+            let code = match argv.get(1).map(|n| n.parse::<i32>()) {
+                None => shell.last_exit_code,
+                Some(Ok(n)) => n & 0xff,
+                Some(Err(_)) => {
+                    writeln!(io.stderr, "return: {}: numeric argument required", argv[1]).ok();
+                    2
+                }
+            };
+            if shell.locals.is_empty() && shell.source_depth == 0 {
+                writeln!(io.stderr, "return: can only `return' from a function or sourced script").ok();
+                return 1;
+            }
+            shell.flow = Some(Flow::Return(code));
+            code
+        }
+        Some("local") => local(&argv[1..], shell, io),
+        Some(name @ ("source" | ".")) => source(name, &argv[1..], shell, io),
+        Some("let") => {
+            if argv.len() < 2 {
+                writeln!(io.stderr, "let: expression expected").ok();
+                return 1;
+            }
+            let mut value = 0;
+            for expr in &argv[1..] {
+                match arith::eval(expr, &mut shell.vars) {
+                    Ok(n) => value = n,
+                    Err(e) => { writeln!(io.stderr, "let: {}", e).ok(); return 1; }
+                }
+            }
+            (value == 0) as i32
+        }
+        Some("shift") => {
+            let count = match argv.get(1).map(|n| n.parse::<usize>()) {
+                None => 1,
+                Some(Ok(n)) => n,
+                Some(Err(_)) => {
+                    writeln!(io.stderr, "shift: {}: numeric argument required", argv[1]).ok();
+                    return 1;
+                }
+            };
+            if count > shell.positional.len() {
+                return 1;
+            }
+            shell.positional.drain(..count);
+            0
+        }
+        Some("echo") => {
+            match writeln!(io.stdout, "{}", argv[1..].join(" ")) {
+                Ok(()) => 0,
+                Err(e) => { writeln!(io.stderr, "echo: write error: {}", e).ok(); 1 }
+            }
+        }
         Some("pwd") => match env::current_dir() {
-            Ok(p) => { println!("{}", p.display()); 0 }
-            Err(e) => { eprintln!("pwd: {}", e); 1 }
+            Ok(p) => { writeln!(io.stdout, "{}", p.display()).ok(); 0 }
+            Err(e) => { writeln!(io.stderr, "pwd: {}", e).ok(); 1 }
         },
         Some("cd") => {
             let path = match argv.get(1) {
                 Some(p) => p.clone(),
-                None => home_dir().map(|p| p.to_string_lossy().into_owned()).unwrap_or_default(),
+                None => home_dir(shell).map(|p| p.to_string_lossy().into_owned()).unwrap_or_default(),
             };
-            let target = resolve_path(&path);
+            let target = resolve_path(&path, shell);
             match env::set_current_dir(&target) {
                 Ok(_) => 0,
-                Err(e) => { eprintln!("cd: {}: {}", target.display(), e); 1 }
-            }
-        }
-        #[cfg(windows)]
-        Some("ls") => {
-            let dir = argv.get(1).map(String::as_str).unwrap_or(".");
-            match std::fs::read_dir(dir) {
-                Ok(entries) => {
-                    for entry in entries.flatten() {
-                        println!("{}", entry.file_name().to_string_lossy());
-                    }
-                    0
-                }
-                Err(e) => { eprintln!("ls: {}: {}", dir, e); 1 }
+                Err(e) => { writeln!(io.stderr, "cd: {}: {}", target.display(), e).ok(); 1 }
             }
         }
         Some("clear") => {
-            #[cfg(windows)]
-            { let _ = std::process::Command::new("cmd").args(["/c", "cls"]).status(); }
-            #[cfg(not(windows))]
-            { print!("\x1b[2J\x1b[H"); io::stdout().flush().ok(); }
-            0
-        }
-        Some("export") => {
-            for arg in &argv[1..] {
-                if let Some((k, v)) = arg.split_once('=') {
-                    unsafe { env::set_var(k, v) };
-                }
-            }
+            write!(io.stdout, "\x1b[2J\x1b[H").ok();
             0
         }
+        Some(name @ ("export" | "readonly" | "declare" | "typeset")) => declare(name, &argv[1..], shell, io),
+        Some("read") => read(&argv[1..], shell, io),
         Some("unset") => {
-            for arg in &argv[1..] {
-                unsafe { env::remove_var(arg) };
+            let (functions, names) = match argv.get(1).map(String::as_str) {
+                Some("-f") => (true, &argv[2..]),
+                Some("-v") => (false, &argv[2..]),
+                _ => (false, &argv[1..]),
+            };
+            let mut code = 0;
+            for name in names {
+                if functions {
+                    shell.functions.remove(name);
+                } else if let Err(e) = shell.vars.unset(name) {
+                    writeln!(io.stderr, "unset: {}", e).ok();
+                    code = 1;
+                }
             }
-            0
+            code
         }
         Some("env") => {
-            for (k, v) in env::vars() {
-                println!("{}={}", k, v);
+            let mut environment: Vec<_> = shell.vars.environment().collect();
+            environment.sort();
+            for (k, v) in environment {
+                writeln!(io.stdout, "{}={}", k, v).ok();
             }
             0
         }
         Some("which") => {
             let mut code = 0;
             for arg in &argv[1..] {
-                match find_in_path(arg) {
-                    Some(p) => println!("{}", p.display()),
-                    None => { eprintln!("{}: not found", arg); code = 1; }
+                match find_in_path(arg, shell.vars.get("PATH").unwrap_or_default()) {
+                    Some(p) => { writeln!(io.stdout, "{}", p.display()).ok(); }
+                    None => { writeln!(io.stderr, "{}: not found", arg).ok(); code = 1; }
                 }
             }
             code
         }
+        Some("shopt") => shopt(&argv[1..], shell, io),
+        Some("set") => set(&argv[1..], shell, io),
+        Some("jobs") => list_jobs(&argv[1..], shell, io),
+        Some(name @ ("fg" | "bg")) => {
+            if shell.terminal.is_none() {
+                writeln!(io.stderr, "{}: no job control", name).ok();
+                return 1;
+            }
+            let id = match job_arg(argv.get(1), shell) {
+                Ok(id) => id,
+                Err(e) => { writeln!(io.stderr, "{}: {}", name, e).ok(); return 1; }
+            };
+            if name == "fg" { jobs::foreground(shell, id, io.stdout) } else { jobs::background(shell, id, io.stdout) }
+        }
+        Some("kill") => kill(&argv[1..], shell, io),
+        Some("disown") => disown(&argv[1..], shell, io),
+        Some("wait") => wait(&argv[1..], shell, io),
         Some("type") => {
             let mut code = 0;
             for arg in &argv[1..] {
-                if is_builtin(arg) {
-                    println!("{} is a shell builtin", arg);
-                } else if let Some(p) = find_in_path(arg) {
-                    println!("{} is {}", arg, p.display());
-                } else {
-                    eprintln!("{}: not found", arg);
+                if !describe_command(arg, true, shell, io) {
+                    writeln!(io.stderr, "{}: not found", arg).ok();
+                    code = 1;
+                }
+            }
+            code
+        }
+        Some("command") => {
+            // Running a command is done by the executor; only the
+            // queries get here.
+            let verbose = match argv.get(1).map(String::as_str) {
+                Some("-v") => false,
+                Some("-V") => true,
+                _ => return 0,
+            };
+            let mut code = 0;
+            for arg in &argv[2..] {
+                if !describe_command(arg, verbose, shell, io) {
+                    if verbose {
+                        writeln!(io.stderr, "command: {}: not found", arg).ok();
+                    }
                     code = 1;
                 }
             }
             code
         }
+        Some("alias") => alias(&argv[1..], shell, io),
+        Some("unalias") => unalias(&argv[1..], shell, io),
         _ => 127,
     }
 }
 
-pub fn find_in_path(name: &str) -> Option<PathBuf> {
-    if name.contains('/') || name.contains('\\') {
-        let p = PathBuf::from(name);
-        return if p.is_file() { Some(p) } else { None };
+/// Print what `name` runs as: for `type` and `command -V` a description,
+/// for `command -v` just the alias, name or path. False if it is not found.
+fn describe_command(name: &str, verbose: bool, shell: &Shell, io: &mut Io) -> bool {
+    if let Some(value) = shell.aliases.get(name) {
+        if verbose {
+            writeln!(io.stdout, "{} is aliased to `{}'", name, value).ok();
+        } else {
+            writeln!(io.stdout, "alias {}={}", name, single_quote(value)).ok();
+        }
+    } else if let Some(body) = shell.functions.get(name) {
+        if verbose {
+            writeln!(io.stdout, "{} is a function", name).ok();
+            writeln!(io.stdout, "{} () {}", name, body).ok();
+        } else {
+            writeln!(io.stdout, "{}", name).ok();
+        }
+    } else if is_builtin(name) {
+        if verbose {
+            writeln!(io.stdout, "{} is a shell builtin", name).ok();
+        } else {
+            writeln!(io.stdout, "{}", name).ok();
+        }
+    } else if let Some(p) = find_in_path(name, shell.vars.get("PATH").unwrap_or_default()) {
+        if verbose {
+            writeln!(io.stdout, "{} is {}", name, p.display()).ok();
+        } else {
+            writeln!(io.stdout, "{}", p.display()).ok();
+        }
+    } else {
+        return false;
     }
-    let path_var = env::var("PATH").unwrap_or_default();
-    for dir in path_var.split(PATH_SEP) {
-        let candidate = Path::new(dir).join(name);
-        if candidate.is_file() {
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                if let Ok(meta) = candidate.metadata() {
-                    if meta.permissions().mode() & 0o111 != 0 {
-                        return Some(candidate);
-                    }
+    true
+}
+
+/// `alias [name[=value]...]`: define aliases, or print them in a form
+/// that can be read back in.
+fn alias(args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    let args = match args.first().map(String::as_str) {
+        Some("-p") => &args[1..],
+        _ => args,
+    };
+    if args.is_empty() {
+        let mut names: Vec<&String> = shell.aliases.keys().collect();
+        names.sort();
+        for name in names {
+            writeln!(io.stdout, "alias {}={}", name, single_quote(&shell.aliases[name])).ok();
+        }
+        return 0;
+    }
+    let mut code = 0;
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, _)) if name.is_empty() || name.contains(['/', '$', '`', '\'', '"', '\\']) => {
+                writeln!(io.stderr, "alias: `{}': invalid alias name", name).ok();
+                code = 1;
+            }
+            Some((name, value)) => {
+                shell.aliases.insert(name.to_string(), value.to_string());
+            }
+            None => match shell.aliases.get(arg) {
+                Some(value) => {
+                    writeln!(io.stdout, "alias {}={}", arg, single_quote(value)).ok();
                 }
-                continue;
+                None => {
+                    writeln!(io.stderr, "alias: {}: not found", arg).ok();
+                    code = 1;
+                }
+            },
+        }
+    }
+    code
+}
+
+/// `unalias [-a] name...`: remove aliases, or with `-a` all of them.
+fn unalias(args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    if args.first().map(String::as_str) == Some("-a") {
+        shell.aliases.clear();
+        return 0;
+    }
+    if args.is_empty() {
+        writeln!(io.stderr, "unalias: usage: unalias [-a] name [name ...]").ok();
+        return 2;
+    }
+    let mut code = 0;
+    for name in args {
+        if shell.aliases.remove(name).is_none() {
+            writeln!(io.stderr, "unalias: {}: not found", name).ok();
+            code = 1;
+        }
+    }
+    code
+}
+
+/// `value` in single quotes, so the shell reads it back unchanged.
+pub fn single_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// `local name[=value]...`: give variables a value that lasts until the
+/// running function returns.
+fn local(args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    let Some(frame) = shell.locals.last_mut() else {
+        writeln!(io.stderr, "local: can only be used in a function").ok();
+        return 1;
+    };
+    let mut code = 0;
+    for arg in args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !parser::is_name(name) {
+            writeln!(io.stderr, "local: `{}': not a valid identifier", arg).ok();
+            code = 1;
+            continue;
+        }
+        // Only the variable as it was before the first `local` is restored.
+        if !frame.iter().any(|(n, _)| n == name) {
+            frame.push((name.to_string(), shell.vars.var(name).cloned()));
+        }
+        let result = match value {
+            Some(value) => shell.vars.set(name, value),
+            None => shell.vars.unset(name),
+        };
+        if let Err(e) = result {
+            writeln!(io.stderr, "local: {}", e).ok();
+            code = 1;
+        }
+    }
+    code
+}
+
+/// `source file [args]` and `. file`: run a file in the current shell.
+/// Arguments replace the positional parameters until it finishes, and
+/// `return` ends it early.
+fn source(builtin: &str, args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    let Some(name) = args.first() else {
+        writeln!(io.stderr, "{}: filename argument required", builtin).ok();
+        return 2;
+    };
+    let Some(path) = find_source(name, shell) else {
+        writeln!(io.stderr, "{}: {}: file not found", builtin, name).ok();
+        return 1;
+    };
+
+    let positional = (args.len() > 1).then(|| std::mem::replace(&mut shell.positional, args[1..].to_vec()));
+    shell.source_depth += 1;
+    let mut code = script::run_file(&path, false, shell).unwrap_or(1);
+    if let Some(Flow::Return(n)) = shell.flow {
+        shell.flow = None;
+        code = n;
+    }
+    shell.source_depth -= 1;
+    if let Some(positional) = positional {
+        shell.positional = positional;
+    }
+    code
+}
+
+/// The file `source` runs: a name without a slash is looked up in `$PATH`,
+/// then in the current directory.
+fn find_source(name: &str, shell: &Shell) -> Option<PathBuf> {
+    if !name.contains('/') {
+        let path_var = shell.vars.get("PATH").unwrap_or_default();
+        let found = path_var.split(PATH_SEP).map(|dir| Path::new(dir).join(name)).find(|p| p.is_file());
+        if found.is_some() {
+            return found;
+        }
+    }
+    let path = PathBuf::from(name);
+    path.is_file().then_some(path)
+}
+
+/// `export`, `readonly`, `declare` and `typeset`: assign variables and set
+/// or clear their attributes. Without names, list the variables that have
+/// the attributes.
+fn declare(builtin: &str, args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    // Attributes to set and to clear: `x` exported, `r` readonly, `i` integer.
+    let mut set = match builtin {
+        "export" => "x".to_string(),
+        "readonly" => "r".to_string(),
+        _ => String::new(),
+    };
+    let mut clear = String::new();
+    let mut print = false;
+    let mut names = args;
+    while let Some(arg) = names.first() {
+        let (flags, on) = match (arg.strip_prefix('-'), arg.strip_prefix('+')) {
+            (Some("-"), _) => {
+                names = &names[1..];
+                break;
             }
-            #[cfg(not(unix))]
-            return Some(candidate);
+            (Some(flags), _) if !flags.is_empty() => (flags, true),
+            (_, Some(flags)) if !flags.is_empty() => (flags, false),
+            _ => break,
+        };
+        for flag in flags.chars() {
+            match (builtin, flag) {
+                (_, 'p') => print = true,
+                ("export", 'n') => clear.push('x'),
+                ("declare" | "typeset", 'i' | 'r' | 'x') if on => set.push(flag),
+                ("declare" | "typeset", 'i' | 'x') => clear.push(flag),
+                _ => {
+                    writeln!(io.stderr, "{}: -{}: invalid option", builtin, flag).ok();
+                    return 2;
+                }
+            }
+        }
+        names = &names[1..];
+    }
+
+    if names.is_empty() {
+        let wanted: String = set.chars().filter(|c| !clear.contains(*c)).collect();
+        for (name, var) in shell.vars.sorted() {
+            let has = |c| match c {
+                'x' => var.exported,
+                'r' => var.readonly,
+                _ => var.integer,
+            };
+            if wanted.chars().all(has) && (print || builtin != "export" || var.exported) {
+                writeln!(io.stdout, "{}", describe_var(builtin, name, var)).ok();
+            }
+        }
+        return 0;
+    }
+
+    let mut code = 0;
+    for arg in names {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !parser::is_name(name) {
+            writeln!(io.stderr, "{}: `{}': not a valid identifier", builtin, arg).ok();
+            code = 1;
+            continue;
+        }
+        if print {
+            match shell.vars.var(name) {
+                Some(var) => { writeln!(io.stdout, "{}", describe_var(builtin, name, var)).ok(); }
+                None => { writeln!(io.stderr, "{}: {}: not found", builtin, name).ok(); code = 1; }
+            }
+            continue;
+        }
+        let var = shell.vars.entry(name);
+        if var.readonly && (value.is_some() || clear.contains('x')) {
+            writeln!(io.stderr, "{}: {}: readonly variable", builtin, name).ok();
+            code = 1;
+            continue;
         }
-        #[cfg(windows)]
+        var.integer = (var.integer || set.contains('i')) && !clear.contains('i');
+        var.exported = (var.exported || set.contains('x')) && !clear.contains('x');
+        if let Some(value) = value
+            && let Err(e) = shell.vars.set(name, value)
         {
-            let exe = Path::new(dir).join(format!("{}.exe", name));
-            if exe.is_file() {
-                return Some(exe);
+            writeln!(io.stderr, "{}: {}", builtin, e).ok();
+            code = 1;
+        }
+        if set.contains('r') {
+            shell.vars.entry(name).readonly = true;
+        }
+    }
+    code
+}
+
+/// A variable as a command that recreates it, such as `export PATH="/bin"`
+/// or `declare -ix n="3"`.
+fn describe_var(builtin: &str, name: &str, var: &Var) -> String {
+    let flags: String = [(var.integer, 'i'), (var.readonly, 'r'), (var.exported, 'x')]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|&(_, c)| c)
+        .collect();
+    let prefix = match builtin {
+        "export" | "readonly" => builtin.to_string(),
+        _ if flags.is_empty() => "declare --".to_string(),
+        _ => format!("declare -{}", flags),
+    };
+    match &var.value {
+        Some(value) => {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('$', "\\$").replace('`', "\\`");
+            format!("{} {}=\"{}\"", prefix, name, value)
+        }
+        None => format!("{} {}", prefix, name),
+    }
+}
+
+/// `set [-+efnuvxC] [-+o name] [--] [arg...]`: turn options on with `-`
+/// or off with `+`, and replace the positional parameters with the
+/// arguments, if any. Without arguments, list the variables.
+fn set(args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    if args.is_empty() {
+        for (name, var) in shell.vars.sorted() {
+            if let Some(value) = &var.value {
+                writeln!(io.stdout, "{}={}", name, single_quote(value)).ok();
+            }
+        }
+        return 0;
+    }
+
+    let mut i = 0;
+    let mut positional = false;
+    while let Some(arg) = args.get(i) {
+        i += 1;
+        let on = match arg.as_str() {
+            "--" => {
+                positional = true;
+                break;
+            }
+            // A lone `-` turns off `-x` and `-v` and ends the options.
+            "-" => {
+                shell.options.xtrace = false;
+                shell.options.verbose = false;
+                positional = i < args.len();
+                break;
+            }
+            _ if arg.starts_with('-') => true,
+            _ if arg.starts_with('+') => false,
+            _ => {
+                i -= 1;
+                positional = true;
+                break;
+            }
+        };
+        for letter in arg[1..].chars() {
+            let name = match letter {
+                'o' => match args.get(i) {
+                    Some(name) => {
+                        i += 1;
+                        name.as_str()
+                    }
+                    None => {
+                        list_options(on, shell, io);
+                        continue;
+                    }
+                },
+                _ => match Options::by_letter(letter) {
+                    Some(name) => name,
+                    None => {
+                        writeln!(io.stderr, "set: {}{}: invalid option", &arg[..1], letter).ok();
+                        return 2;
+                    }
+                },
+            };
+            let Some(value) = shell.options.get_mut(name) else {
+                writeln!(io.stderr, "set: {}: invalid option name", name).ok();
+                return 2;
+            };
+            *value = on;
+        }
+    }
+    if positional {
+        shell.positional = args[i..].to_vec();
+    }
+    0
+}
+
+/// `set -o` lists the options as a table, `set +o` as commands that set
+/// them again.
+fn list_options(table: bool, shell: &Shell, io: &mut Io) {
+    for name in Options::NAMES {
+        let on = shell.options.get(name).unwrap_or_default();
+        if table {
+            writeln!(io.stdout, "{:<15} {}", name, if on { "on" } else { "off" }).ok();
+        } else {
+            writeln!(io.stdout, "set {}o {}", if on { '-' } else { '+' }, name).ok();
+        }
+    }
+}
+
+/// `shopt [-s|-u|-q] [name...]`: set, unset or query shell options.
+fn shopt(args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    let mut set = None;
+    let mut quiet = false;
+    let mut names = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-s" => set = Some(true),
+            "-u" => set = Some(false),
+            "-q" => quiet = true,
+            _ => names.push(arg.as_str()),
+        }
+    }
+    if names.is_empty() {
+        for name in Shopts::NAMES {
+            let value = shell.shopts.get(name).unwrap_or_default();
+            if set.is_none_or(|on| on == value) && !quiet {
+                writeln!(io.stdout, "{:<15} {}", name, if value { "on" } else { "off" }).ok();
+            }
+        }
+        return 0;
+    }
+
+    let mut code = 0;
+    for name in names {
+        let Some(value) = shell.shopts.get_mut(name) else {
+            writeln!(io.stderr, "shopt: {}: invalid shell option name", name).ok();
+            code = 1;
+            continue;
+        };
+        match set {
+            Some(on) => *value = on,
+            None => {
+                if !*value {
+                    code = 1;
+                }
+                if !quiet {
+                    writeln!(io.stdout, "{:<15} {}", name, if *value { "on" } else { "off" }).ok();
+                }
+            }
+        }
+    }
+    code
+}
+
+/// `read [-r] [name...]`: read a line from stdin and split it on blanks
+/// into the named variables, the last one taking the rest of the line.
+/// Without names the line goes into `REPLY`. Unless `-r`, a backslash
+/// quotes the next character and joins lines.
+fn read(args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    let raw = args.first().is_some_and(|a| a == "-r");
+    let names = if raw { &args[1..] } else { args };
+
+    let mut line = Vec::new();
+    // Bytes that were quoted by a backslash and so never split the line.
+    let mut quoted = Vec::new();
+    let mut byte = [0u8];
+    let mut eof = false;
+    loop {
+        match io.stdin.read(&mut byte) {
+            Ok(1) => {}
+            Ok(_) => { eof = true; break; }
+            Err(e) => { writeln!(io.stderr, "read: {}", e).ok(); return 1; }
+        }
+        match byte[0] {
+            b'\n' => break,
+            b'\\' if !raw => {
+                match io.stdin.read(&mut byte) {
+                    Ok(1) if byte[0] == b'\n' => {}
+                    Ok(1) => {
+                        quoted.push(line.len());
+                        line.push(byte[0]);
+                    }
+                    _ => { eof = true; break; }
+                }
+            }
+            b => line.push(b),
+        }
+    }
+    let blank = |i: usize| matches!(line[i], b' ' | b'\t') && !quoted.contains(&i);
+    let text = |range: std::ops::Range<usize>| String::from_utf8_lossy(&line[range]).into_owned();
+    let reply = ["REPLY".to_string()];
+    let names = if names.is_empty() { &reply[..] } else { names };
+
+    let mut i = 0;
+    for (n, name) in names.iter().enumerate() {
+        while i < line.len() && blank(i) {
+            i += 1;
+        }
+        let start = i;
+        let value = if n + 1 == names.len() {
+            // The last variable takes the rest of the line.
+            let mut end = line.len();
+            while end > start && blank(end - 1) {
+                end -= 1;
+            }
+            text(start..end)
+        } else {
+            while i < line.len() && !blank(i) {
+                i += 1;
+            }
+            text(start..i)
+        };
+        if let Err(e) = shell.vars.set(name, value) {
+            writeln!(io.stderr, "read: {}", e).ok();
+            return 1;
+        }
+    }
+    // A last line without a newline is still read, but reported as the end.
+    if eof { 1 } else { 0 }
+}
+
+/// The job named by a job spec, or the current job if there is none.
+fn job_arg(spec: Option<&String>, shell: &Shell) -> Result<usize, String> {
+    match spec {
+        Some(spec) => shell.jobs.resolve(spec),
+        None => shell.jobs.current().ok_or_else(|| "current: no such job".to_string()),
+    }
+}
+
+/// `jobs [-l|-p] [spec...]`: list jobs and their states.
+fn list_jobs(args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    let mut long = false;
+    let mut pids_only = false;
+    let mut specs = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "-l" => long = true,
+            "-p" => pids_only = true,
+            _ => specs.push(arg),
+        }
+    }
+    shell.jobs.poll();
+    let ids = if specs.is_empty() {
+        shell.jobs.ids()
+    } else {
+        let mut ids = Vec::new();
+        for spec in specs {
+            match shell.jobs.resolve(spec) {
+                Ok(id) => ids.push(id),
+                Err(e) => { writeln!(io.stderr, "jobs: {}", e).ok(); return 1; }
+            }
+        }
+        ids
+    };
+    for id in ids {
+        match shell.jobs.get(id) {
+            Some(job) if pids_only => { writeln!(io.stdout, "{}", job.pgid).ok(); }
+            Some(_) => { writeln!(io.stdout, "{}", shell.jobs.describe(id, long)).ok(); }
+            None => {}
+        }
+    }
+    shell.jobs.forget_reported();
+    0
+}
+
+/// `kill [-s sig | -n num | -sig] pid|%job...` and `kill -l [num...]`.
+fn kill(args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    let mut signal = libc::SIGTERM;
+    let mut rest = args;
+    match rest.first().map(String::as_str) {
+        Some("-l") | Some("-L") => {
+            if rest.len() == 1 {
+                for (name, number) in sys::signal_names() {
+                    writeln!(io.stdout, "{:>2}) SIG{}", number, name).ok();
+                }
+                return 0;
+            }
+            let mut code = 0;
+            for arg in &rest[1..] {
+                // Exit statuses of signalled commands are 128 + the signal.
+                let number = arg.parse::<i32>().map(|n| if n > 128 { n - 128 } else { n });
+                match sys::signal_names().find(|&(_, n)| Ok(n) == number) {
+                    Some((name, _)) => { writeln!(io.stdout, "{}", name).ok(); }
+                    None => { writeln!(io.stderr, "kill: {}: invalid signal specification", arg).ok(); code = 1; }
+                }
+            }
+            return code;
+        }
+        Some("-s") | Some("-n") => {
+            let Some(name) = rest.get(1) else {
+                writeln!(io.stderr, "kill: {}: option requires an argument", rest[0]).ok();
+                return 2;
+            };
+            match sys::signal_number(name) {
+                Some(sig) => signal = sig,
+                None => { writeln!(io.stderr, "kill: {}: invalid signal specification", name).ok(); return 1; }
+            }
+            rest = &rest[2..];
+        }
+        Some(opt) if opt.starts_with('-') && opt.len() > 1 => {
+            match sys::signal_number(&opt[1..]) {
+                Some(sig) => signal = sig,
+                None => { writeln!(io.stderr, "kill: {}: invalid signal specification", &opt[1..]).ok(); return 1; }
+            }
+            rest = &rest[1..];
+        }
+        _ => {}
+    }
+    if rest.is_empty() {
+        writeln!(io.stderr, "kill: usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ... or kill -l [sigspec]").ok();
+        return 2;
+    }
+
+    let mut code = 0;
+    for target in rest {
+        let (pid, stopped) = if target.starts_with('%') {
+            match shell.jobs.resolve(target) {
+                Ok(id) => {
+                    let job = shell.jobs.get(id).expect("resolved job exists");
+                    let pid = if job.grouped { -job.pgid } else { job.pgid };
+                    (pid, job.state == JobState::Stopped)
+                }
+                Err(e) => { writeln!(io.stderr, "kill: {}", e).ok(); code = 1; continue; }
             }
+        } else {
+            match target.parse::<Pid>() {
+                Ok(pid) => (pid, false),
+                Err(_) => {
+                    writeln!(io.stderr, "kill: {}: arguments must be process or job IDs", target).ok();
+                    code = 1;
+                    continue;
+                }
+            }
+        };
+        if let Err(e) = sys::kill(pid, signal) {
+            writeln!(io.stderr, "kill: ({}) - {}", target, e).ok();
+            code = 1;
+        } else if stopped && matches!(signal, libc::SIGTERM | libc::SIGHUP) {
+            // A stopped job only acts on these once it runs again.
+            sys::kill(pid, libc::SIGCONT).ok();
+        }
+    }
+    code
+}
+
+/// `disown [-a] [spec...]`: forget jobs without signalling them.
+fn disown(args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    if args.iter().any(|a| a == "-a") {
+        for id in shell.jobs.ids() {
+            shell.jobs.remove(id);
+        }
+        return 0;
+    }
+    let mut code = 0;
+    let specs: Vec<Option<&String>> = if args.is_empty() { vec![None] } else { args.iter().map(Some).collect() };
+    for spec in specs {
+        match job_arg(spec, shell) {
+            Ok(id) => { shell.jobs.remove(id); }
+            Err(e) => { writeln!(io.stderr, "disown: {}", e).ok(); code = 1; }
+        }
+    }
+    code
+}
+
+/// `wait [pid|%job...]`: wait for the given jobs, or all of them. Returns
+/// the status of the last one.
+fn wait(args: &[String], shell: &mut Shell, io: &mut Io) -> i32 {
+    if args.is_empty() {
+        for id in shell.jobs.ids() {
+            shell.jobs.wait_for(id);
+        }
+        return 0;
+    }
+    let mut code = 0;
+    for arg in args {
+        let id = if arg.starts_with('%') {
+            match shell.jobs.resolve(arg) {
+                Ok(id) => id,
+                Err(e) => { writeln!(io.stderr, "wait: {}", e).ok(); code = 127; continue; }
+            }
+        } else {
+            match arg.parse::<Pid>().ok().and_then(|pid| shell.jobs.find_pid(pid)) {
+                Some(id) => id,
+                None => {
+                    writeln!(io.stderr, "wait: pid {} is not a child of this shell", arg).ok();
+                    code = 127;
+                    continue;
+                }
+            }
+        };
+        code = shell.jobs.wait_for(id);
+    }
+    code
+}
+
+/// Look for an executable `name` in the directories of `path_var`.
+pub fn find_in_path(name: &str, path_var: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        let p = PathBuf::from(name);
+        return if p.is_file() { Some(p) } else { None };
+    }
+    for dir in path_var.split(PATH_SEP) {
+        let candidate = Path::new(dir).join(name);
+        if let Ok(meta) = candidate.metadata()
+            && meta.is_file()
+            && meta.permissions().mode() & 0o111 != 0
+        {
+            return Some(candidate);
         }
     }
     None
 }
 
-fn resolve_path(path: &str) -> PathBuf {
-    if path == "~" || path.starts_with("~/") || path.starts_with("~\\") {
-        let home = home_dir().unwrap_or_else(|| PathBuf::from("/"));
+fn resolve_path(path: &str, shell: &Shell) -> PathBuf {
+    if path == "~" || path.starts_with("~/") {
+        let home = home_dir(shell).unwrap_or_else(|| PathBuf::from("/"));
         if path.len() > 1 { home.join(&path[2..]) } else { home }
     } else {
         PathBuf::from(path)
@@ -1,13 +1,15 @@
 use std::borrow::Cow;
 use std::fs;
+use std::os::unix::fs::PermissionsExt;
 
 use rustyline::completion::{Completer, FilenameCompleter, Pair};
 use rustyline::highlight::Highlighter;
 use rustyline::hint::Hinter;
-use rustyline::validate::Validator;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
 use rustyline::{Context, Helper};
 
 use crate::builtins::BUILTINS;
+use crate::parser;
 
 pub struct ShellHelper {
     pub path_commands: Vec<String>,
@@ -99,42 +101,42 @@ impl Hinter for ShellHelper {
     }
 }
 
-impl Validator for ShellHelper {}
+impl Validator for ShellHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        // rustyline can't draw a prompt on continuation rows, so a fresh line
+        // goes back to the REPL, which reads the rest with PS2. Only an entry
+        // that already spans several lines (recalled from history) is kept
+        // open here.
+        let input = ctx.input();
+        if input.contains('\n') && is_incomplete(input) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+/// True if the input stops in the middle of a command: an open quote, a
+/// trailing `\`, `|` or `&&`, or an unclosed `{`.
+pub fn is_incomplete(input: &str) -> bool {
+    matches!(parser::parse(input), Err(e) if e.incomplete)
+}
 
 /// Scan PATH and return sorted, deduplicated executable names.
 pub fn collect_path_commands() -> Vec<String> {
     let path_var = std::env::var("PATH").unwrap_or_default();
     let mut cmds: Vec<String> = Vec::new();
 
-    #[cfg(windows)]
-    const PATH_SEP: char = ';';
-    #[cfg(not(windows))]
-    const PATH_SEP: char = ':';
-
-    for dir in path_var.split(PATH_SEP) {
+    for dir in path_var.split(':') {
         let Ok(entries) = fs::read_dir(dir) else { continue };
         for entry in entries.flatten() {
             let path = entry.path();
             if !path.is_file() {
                 continue;
             }
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                let Ok(meta) = entry.metadata() else { continue };
-                if meta.permissions().mode() & 0o111 == 0 {
-                    continue;
-                }
-            }
-            #[cfg(windows)]
-            {
-                let ext = path.extension()
-                    .and_then(|e| e.to_str())
-                    .unwrap_or("")
-                    .to_ascii_lowercase();
-                if !["exe", "bat", "cmd", "com"].contains(&ext.as_str()) {
-                    continue;
-                }
+            let Ok(meta) = entry.metadata() else { continue };
+            if meta.permissions().mode() & 0o111 == 0 {
+                continue;
             }
             if let Some(name) = path.file_name() {
                 cmds.push(name.to_string_lossy().into_owned());
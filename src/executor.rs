@@ -1,165 +1,900 @@
-use std::fs::{File, OpenOptions};
-use std::process::{Command, Stdio};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::{FromRawFd, IntoRawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command as Process;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::ast::{
+    AndOr, AndOrOp, Assignment, CaseItem, CaseTerminator, Command, CompoundCmd, List, Pipeline, Redirect,
+    RedirectKind, SimpleCmd,
+};
 use crate::builtins;
-use crate::parser::{CommandList, Pipeline, RunIf, SimpleCmd};
-use crate::shell::Shell;
+use crate::expand::{expand_arith, expand_pattern, expand_word, expand_words, ExpandError};
+use crate::glob;
+use crate::jobs::{self, Job};
+use crate::shell::{Flow, Shell};
+use crate::lexer::Word;
+use crate::sys::{self, Pid};
 
-/// Run all pipelines in the list sequentially.
+/// Run a parsed list at the top level.
 /// Returns false if the shell should exit.
-pub fn run_list(list: CommandList, shell: &mut Shell) -> bool {
-    for (pipeline, run_if) in list {
-        let should_run = match run_if {
-            RunIf::Always => true,
-            RunIf::OnSuccess => shell.last_exit_code == 0,
-            RunIf::OnFailure => shell.last_exit_code != 0,
+pub fn run_list(list: &List, shell: &mut Shell) -> bool {
+    exec_list(list, shell);
+    !matches!(shell.flow, Some(Flow::Exit(_)))
+}
+
+/// Run every and-or list in turn, stopping early if a builtin requested it.
+pub fn exec_list(list: &List, shell: &mut Shell) -> i32 {
+    for and_or in &list.items {
+        if and_or.background {
+            run_background(and_or, shell);
+        } else {
+            exec_and_or(and_or, shell);
+        }
+        if shell.flow.is_some() || sys::interrupted() {
+            break;
+        }
+    }
+    shell.last_exit_code
+}
+
+fn exec_and_or(and_or: &AndOr, shell: &mut Shell) -> i32 {
+    // Every pipeline but the last is tested by the `&&` or `||` after it.
+    let tested = !and_or.rest.is_empty();
+    shell.last_exit_code = as_condition(tested, shell, |shell| run_pipeline(&and_or.first, shell));
+    let mut last_ran = !tested && !and_or.first.negated;
+    for (i, (op, pipeline)) in and_or.rest.iter().enumerate() {
+        if shell.flow.is_some() || sys::interrupted() {
+            break;
+        }
+        let should_run = match op {
+            AndOrOp::And => shell.last_exit_code == 0,
+            AndOrOp::Or => shell.last_exit_code != 0,
         };
         if should_run {
-            let code = run_pipeline(pipeline, shell);
-            if code == -1 {
-                return false; // exit sentinel
-            }
-            shell.last_exit_code = code;
+            let tested = i + 1 < and_or.rest.len();
+            shell.last_exit_code = as_condition(tested, shell, |shell| run_pipeline(pipeline, shell));
+            last_ran = !tested && !pipeline.negated;
         }
     }
-    true
+    if last_ran {
+        check_errexit(shell);
+    }
+    shell.last_exit_code
+}
+
+/// Run `f` with `set -e` suspended if `tested` is set, because the caller
+/// looks at its status.
+fn as_condition<T>(tested: bool, shell: &mut Shell, f: impl FnOnce(&mut Shell) -> T) -> T {
+    if !tested {
+        return f(shell);
+    }
+    shell.condition_depth += 1;
+    let result = f(shell);
+    shell.condition_depth -= 1;
+    result
 }
 
-fn run_pipeline(pipeline: Pipeline, shell: &mut Shell) -> i32 {
-    if pipeline.is_empty() {
-        return 0;
+/// For `set -e`: exit if the last command failed outside a condition.
+fn check_errexit(shell: &mut Shell) {
+    if shell.options.errexit && shell.condition_depth == 0 && shell.last_exit_code != 0 && shell.flow.is_none() {
+        shell.flow = Some(Flow::Exit(shell.last_exit_code));
     }
+}
 
-    // Single command: run builtins in-process
-    if pipeline.len() == 1 {
-        let cmd = &pipeline[0];
-        if !cmd.argv.is_empty() && builtins::is_builtin(&cmd.argv[0]) {
-            return builtins::run(&cmd.argv, shell);
+/// Start an and-or list in a forked shell without waiting for it, and
+/// record it in the job table.
+fn run_background(and_or: &AndOr, shell: &mut Shell) {
+    // Without job control the job stays in the shell's process group.
+    let pgid = job_pgid(shell);
+    match fork_shell(shell, pgid, false) {
+        Ok(0) => {
+            if pgid.is_none() {
+                sys::ignore_interrupts();
+            }
+            // A lone program replaces the forked shell, so that signals
+            // sent to the job reach it.
+            let code = match and_or.first.commands.as_slice() {
+                [Command::Simple(simple)] if and_or.rest.is_empty() && !and_or.first.negated => {
+                    run_simple(simple, true, shell)
+                }
+                _ => exec_and_or(and_or, shell),
+            };
+            sys::exit_child(child_exit_code(shell, code));
+        }
+        Ok(pid) => {
+            let id = shell.jobs.insert(Job::new(pgid, vec![pid], format!("{} &", and_or)));
+            if shell.interactive {
+                eprintln!("[{}] {}", id, pid);
+            }
+            shell.last_bg_pid = Some(pid);
+            shell.last_exit_code = 0;
+        }
+        Err(e) => {
+            eprintln!("rsh: fork: {}", e);
+            shell.last_exit_code = 1;
         }
-        return spawn_single(cmd);
     }
+}
 
-    // Multi-command pipeline
-    run_pipe_chain(pipeline)
+/// Run a pipeline and record the status of each of its commands in
+/// `PIPESTATUS`. Returns its status, inverted if it is negated with `!`.
+fn run_pipeline(pipeline: &Pipeline, shell: &mut Shell) -> i32 {
+    // The status of a negated pipeline is being tested, so `set -e` does
+    // not apply inside it.
+    let code = as_condition(pipeline.negated, shell, |shell| match pipeline.commands.as_slice() {
+        [] => 0,
+        [cmd] => {
+            let code = run_command(cmd, shell);
+            shell.pipe_status = vec![code];
+            code
+        }
+        cmds => run_pipe_chain(cmds, shell),
+    });
+    if pipeline.negated { (code == 0) as i32 } else { code }
 }
 
-fn spawn_single(cmd: &SimpleCmd) -> i32 {
-    if cmd.argv.is_empty() {
-        return 0;
+fn run_command(cmd: &Command, shell: &mut Shell) -> i32 {
+    match cmd {
+        Command::Simple(simple) => run_simple(simple, false, shell),
+        Command::Compound(compound, redirects) => {
+            let _saved = match redirect_shell(redirects, shell) {
+                Ok(saved) => saved,
+                Err(code) => return code,
+            };
+            run_compound(compound, shell)
+        }
+        Command::Function(def) => {
+            shell.functions.insert(def.name.clone(), def.body.clone());
+            0
+        }
     }
+}
 
-    let program = match builtins::find_in_path(&cmd.argv[0]) {
-        Some(p) => p,
-        None => { eprintln!("{}: command not found", cmd.argv[0]); return 127; }
+/// Expand and run a simple command. In a child with nothing left to do
+/// (`last`), a program replaces the process rather than being waited for.
+fn run_simple(cmd: &SimpleCmd, last: bool, shell: &mut Shell) -> i32 {
+    let argv = match expand_words(&cmd.words, shell) {
+        Ok(argv) => argv,
+        Err(e) => { eprintln!("{}{}", shell.error_prefix(), e); return 1; }
     };
+    if argv.is_empty() && assign(&cmd.assignments, shell) != 0 {
+        return 1;
+    }
+    if let Some(last) = argv.last() {
+        shell.last_arg = last.clone();
+    }
+    let env = match prefix_env(&cmd.assignments, &argv, shell) {
+        Ok(env) => env,
+        Err(e) => { eprintln!("{}{}", shell.error_prefix(), e); return 1; }
+    };
+    if !argv.is_empty() {
+        trace(&env, &argv, shell);
+    }
+    // Single command: run functions and builtins in-process
+    if !argv.is_empty() && is_internal(&argv[0], shell) {
+        let _saved = match redirect_shell(&cmd.redirects, shell) {
+            Ok(saved) => saved,
+            Err(code) => return code,
+        };
+        return run_internal(&argv, &env, shell);
+    }
+    if last && !argv.is_empty() {
+        return match redirect_shell(&cmd.redirects, shell) {
+            Ok(_saved) => exec_external(&argv, &env, shell),
+            Err(code) => code,
+        };
+    }
+    let code = spawn_single(&argv, &env, cmd, shell);
+    // A command made only of substitutions takes their status.
+    let mut words = cmd.words.iter().chain(cmd.assignments.iter().map(|a| &a.value));
+    if argv.is_empty() && code == 0 && words.any(|w| w.has_command_sub()) {
+        return shell.last_exit_code;
+    }
+    code
+}
 
-    let stdin = make_stdin(&cmd.stdin_file);
-    let stdout = make_stdout(&cmd.stdout_file, cmd.append);
+/// Set shell variables from `name=value` words. Returns 1 if one of them
+/// is readonly.
+fn assign(assignments: &[Assignment], shell: &mut Shell) -> i32 {
+    for assignment in assignments {
+        let result = expand_word(&assignment.value, shell).map_err(|e| e.to_string()).and_then(|value| {
+            trace(&[(assignment.name.clone(), value.clone())], &[], shell);
+            shell.vars.set(&assignment.name, value)
+        });
+        if let Err(e) = result {
+            eprintln!("{}{}", shell.error_prefix(), e);
+            return 1;
+        }
+    }
+    0
+}
+
+/// For `set -x`: print `$PS4` and the command about to run to stderr,
+/// quoting words that would not read back the same.
+fn trace(env: &[(String, String)], argv: &[String], shell: &Shell) {
+    if !shell.options.xtrace {
+        return;
+    }
+    let quote = |word: &str| {
+        let plain = |c: char| c.is_ascii_alphanumeric() || "_-./:=,+%@^".contains(c);
+        if !word.is_empty() && word.chars().all(plain) { word.to_string() } else { builtins::single_quote(word) }
+    };
+    let assignments = env.iter().map(|(name, value)| format!("{}={}", name, quote(value)));
+    let words = argv.iter().map(|word| quote(word));
+    let line: Vec<String> = assignments.chain(words).collect();
+    eprintln!("{}{}", shell.vars.get("PS4").unwrap_or("+ "), line.join(" "));
+}
 
-    match Command::new(&program)
-        .args(&cmd.argv[1..])
-        .stdin(stdin)
-        .stdout(stdout)
-        .spawn()
-    {
-        Ok(mut child) => child.wait().map(|s| s.code().unwrap_or(0)).unwrap_or(1),
-        Err(e) => { eprintln!("{}: {}", cmd.argv[0], e); 1 }
+/// The `name=value` pairs that `assignments` add to the environment of
+/// the command `argv`; none if there is no command and they were set as
+/// shell variables instead.
+fn prefix_env(assignments: &[Assignment], argv: &[String], shell: &mut Shell) -> Result<Vec<(String, String)>, ExpandError> {
+    if argv.is_empty() {
+        return Ok(Vec::new());
     }
+    assignments.iter().map(|a| Ok((a.name.clone(), expand_word(&a.value, shell)?))).collect()
 }
 
-fn run_pipe_chain(pipeline: Pipeline) -> i32 {
-    let last = pipeline.len() - 1;
-    let mut prev_stdout: Option<std::process::ChildStdout> = None;
-    let mut children: Vec<std::process::Child> = Vec::new();
+/// Functions and builtins run inside the shell rather than as a new
+/// program.
+fn is_internal(name: &str, shell: &Shell) -> bool {
+    shell.functions.contains_key(name) || builtins::is_builtin(name)
+}
 
-    for (i, cmd) in pipeline.iter().enumerate() {
-        if cmd.argv.is_empty() {
-            continue;
+/// Run a function, or failing that a builtin, with the variables in `env`
+/// set and exported only until it is done.
+fn run_internal(argv: &[String], env: &[(String, String)], shell: &mut Shell) -> i32 {
+    let mut saved = Vec::new();
+    let mut code = None;
+    for (name, value) in env {
+        saved.push((name.clone(), shell.vars.var(name).cloned()));
+        if let Err(e) = shell.vars.set(name, value.clone()) {
+            eprintln!("{}{}", shell.error_prefix(), e);
+            code = Some(1);
+            break;
         }
+        shell.vars.entry(name).exported = true;
+    }
+    let code = code.unwrap_or_else(|| match shell.functions.get(&argv[0]).cloned() {
+        Some(body) => call_function(&body, argv, shell),
+        None if argv[0] == "command" => run_skipping_functions(&argv[1..], shell),
+        None => run_builtin(argv, shell),
+    });
+    for (name, var) in saved.into_iter().rev() {
+        shell.vars.restore(&name, var);
+    }
+    code
+}
 
-        let is_last = i == last;
+/// `command name [args]`: run a builtin or program even if a function has
+/// the same name. The `-v` and `-V` queries are left to the builtin.
+fn run_skipping_functions(argv: &[String], shell: &mut Shell) -> i32 {
+    let mut argv = argv;
+    while argv.first().is_some_and(|arg| arg == "command" || arg == "--") {
+        argv = &argv[1..];
+    }
+    match argv.first().map(String::as_str) {
+        None => 0,
+        Some("-v" | "-V") => run_builtin(&[&["command".to_string()], argv].concat(), shell),
+        Some(name) if builtins::is_builtin(name) => run_builtin(argv, shell),
+        Some(_) => match spawn_external(argv, &[], job_pgid(shell), shell) {
+            Ok(pid) => jobs::wait_foreground(shell, Job::new(job_pgid(shell), vec![pid], argv.join(" "))),
+            Err(code) => code,
+        },
+    }
+}
+
+/// Call a function with `argv[1..]` as its positional parameters. Its
+/// `local` variables are restored when it returns.
+fn call_function(body: &Command, argv: &[String], shell: &mut Shell) -> i32 {
+    let positional = std::mem::replace(&mut shell.positional, argv[1..].to_vec());
+    let loop_depth = std::mem::take(&mut shell.loop_depth);
+    shell.locals.push(Vec::new());
+
+    let mut code = run_command(body, shell);
+    if let Some(Flow::Return(n)) = shell.flow {
+        shell.flow = None;
+        code = n;
+    }
+
+    for (name, var) in shell.locals.pop().unwrap_or_default().into_iter().rev() {
+        shell.vars.restore(&name, var);
+    }
+    shell.loop_depth = loop_depth;
+    shell.positional = positional;
+    code
+}
+
+/// Run a builtin on the shell's standard streams, which redirections may
+/// have pointed elsewhere.
+fn run_builtin(argv: &[String], shell: &mut Shell) -> i32 {
+    let mut stderr = Located { prefix: shell.location(), line_start: true, inner: io::stderr() };
+    let (mut stdin, mut stdout) = (sys::FdReader(0), io::stdout());
+    let mut streams = builtins::Io { stdin: &mut stdin, stdout: &mut stdout, stderr: &mut stderr };
+    let code = builtins::run(argv, shell, &mut streams);
+    stdout.flush().ok();
+    code
+}
+
+/// A builtin's standard error, with every line it writes starting with
+/// where the command came from.
+struct Located<W> {
+    prefix: String,
+    line_start: bool,
+    inner: W,
+}
+
+impl<W: Write> Write for Located<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.line_start {
+            self.inner.write_all(self.prefix.as_bytes())?;
+            self.line_start = false;
+        }
+        // Up to the end of the line, so the next write gets a prefix.
+        let len = buf.iter().position(|&b| b == b'\n').map_or(buf.len(), |i| i + 1);
+        let n = self.inner.write(&buf[..len])?;
+        self.line_start = n == len && buf[len - 1] == b'\n';
+        Ok(n)
+    }
 
-        let stdin: Stdio = if let Some(out) = prev_stdout.take() {
-            Stdio::from(out)
-        } else if let Some(ref f) = cmd.stdin_file {
-            match File::open(f) {
-                Ok(file) => Stdio::from(file),
-                Err(e) => { eprintln!("{}: {}", f, e); return 1; }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn run_compound(compound: &CompoundCmd, shell: &mut Shell) -> i32 {
+    match compound {
+        CompoundCmd::BraceGroup(list) => exec_list(list, shell),
+        CompoundCmd::Subshell(list) => subshell(list, shell),
+        CompoundCmd::If { branches, otherwise } => {
+            for (condition, body) in branches {
+                as_condition(true, shell, |shell| exec_list(condition, shell));
+                if shell.flow.is_some() {
+                    return shell.last_exit_code;
+                }
+                if shell.last_exit_code == 0 {
+                    return exec_list(body, shell);
+                }
             }
-        } else {
-            Stdio::inherit()
-        };
+            match otherwise {
+                Some(body) => exec_list(body, shell),
+                None => 0,
+            }
+        }
+        CompoundCmd::While { until, condition, body } => {
+            let mut status = 0;
+            shell.loop_depth += 1;
+            loop {
+                as_condition(true, shell, |shell| exec_list(condition, shell));
+                if loop_done(shell) || (shell.last_exit_code == 0) == *until {
+                    break;
+                }
+                status = exec_list(body, shell);
+                if loop_done(shell) {
+                    break;
+                }
+            }
+            shell.loop_depth -= 1;
+            status
+        }
+        CompoundCmd::For { name, words, body } => {
+            let values = match words {
+                Some(words) => match expand_words(words, shell) {
+                    Ok(values) => values,
+                    Err(e) => { eprintln!("{}{}", shell.error_prefix(), e); return 1; }
+                },
+                None => shell.positional.clone(),
+            };
+            let mut status = 0;
+            shell.loop_depth += 1;
+            for value in values {
+                if let Err(e) = shell.vars.set(name, value) {
+                    eprintln!("{}{}", shell.error_prefix(), e);
+                    status = 1;
+                    break;
+                }
+                status = exec_list(body, shell);
+                if loop_done(shell) {
+                    break;
+                }
+            }
+            shell.loop_depth -= 1;
+            status
+        }
+        CompoundCmd::ArithFor { init, condition, step, body } => {
+            let mut status = 0;
+            shell.loop_depth += 1;
+            let result = (|| {
+                expand_arith(init, shell)?;
+                while condition.parts.is_empty() || expand_arith(condition, shell)? != 0 {
+                    status = exec_list(body, shell);
+                    if loop_done(shell) {
+                        break;
+                    }
+                    expand_arith(step, shell)?;
+                }
+                Ok::<(), ExpandError>(())
+            })();
+            shell.loop_depth -= 1;
+            match result {
+                Ok(()) => status,
+                Err(e) => { eprintln!("{}{}", shell.error_prefix(), e); 1 }
+            }
+        }
+        CompoundCmd::Arith(expr) => match expand_arith(expr, shell) {
+            Ok(value) => (value == 0) as i32,
+            Err(e) => { eprintln!("{}{}", shell.error_prefix(), e); 1 }
+        },
+        CompoundCmd::Case { word, items } => run_case(word, items, shell),
+    }
+}
+
+/// Handle `break` and `continue` after part of a loop has run. Returns
+/// true if the loop should stop.
+fn loop_done(shell: &mut Shell) -> bool {
+    match shell.flow {
+        Some(Flow::Break(n)) => {
+            shell.flow = (n > 1).then(|| Flow::Break(n - 1));
+            true
+        }
+        Some(Flow::Continue(n)) if n > 1 => {
+            shell.flow = Some(Flow::Continue(n - 1));
+            true
+        }
+        Some(Flow::Continue(_)) => {
+            shell.flow = None;
+            false
+        }
+        Some(_) => true,
+        None => sys::interrupted(),
+    }
+}
 
-        let stdout: Stdio = if is_last {
-            make_stdout(&cmd.stdout_file, cmd.append)
+/// Run the body of the first matching `case` item, then carry on as its
+/// terminator says.
+fn run_case(word: &Word, items: &[CaseItem], shell: &mut Shell) -> i32 {
+    let subject = match expand_word(word, shell) {
+        Ok(subject) => subject,
+        Err(e) => { eprintln!("{}{}", shell.error_prefix(), e); return 1; }
+    };
+    let mut status = 0;
+    let mut fall_through = false;
+    for item in items {
+        if !fall_through {
+            let mut matched = false;
+            for pattern in &item.patterns {
+                match expand_pattern(pattern, shell) {
+                    Ok(pattern) if glob::matches(&pattern, &subject) => {
+                        matched = true;
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(e) => { eprintln!("{}{}", shell.error_prefix(), e); return 1; }
+                }
+            }
+            if !matched {
+                continue;
+            }
+        }
+        status = exec_list(&item.body, shell);
+        if shell.flow.is_some() {
+            break;
+        }
+        match item.terminator {
+            CaseTerminator::Break => break,
+            CaseTerminator::FallThrough => fall_through = true,
+            CaseTerminator::Continue => fall_through = false,
+        }
+    }
+    status
+}
+
+/// Run `list` in a forked copy of the shell as a foreground job.
+fn subshell(list: &List, shell: &mut Shell) -> i32 {
+    let pgid = job_pgid(shell);
+    match fork_shell(shell, pgid, true) {
+        Ok(0) => {
+            let code = exec_list(list, shell);
+            sys::exit_child(child_exit_code(shell, code))
+        }
+        Ok(pid) => jobs::wait_foreground(shell, Job::new(pgid, vec![pid], format!("({})", list))),
+        Err(e) => { eprintln!("rsh: fork: {}", e); 1 }
+    }
+}
+
+/// The process group for a new foreground job: a fresh one under job
+/// control, otherwise none, so the job stays in the shell's group.
+fn job_pgid(shell: &Shell) -> Option<Pid> {
+    shell.terminal.as_ref().map(|_| 0)
+}
+
+/// Move a new child into process group `pgid` (0 for a group of its own).
+/// Both parent and child do this, so neither has to wait for the other.
+fn join_group(pid: Pid, pgid: Option<Pid>) {
+    if let Some(pgid) = pgid {
+        sys::setpgid(pid, if pgid == 0 { pid } else { pgid }).ok();
+    }
+}
+
+/// Fork a copy of the shell. The child joins process group `pgid`, takes
+/// the terminal if it is a `foreground` job, and does no job control of
+/// its own.
+fn fork_shell(shell: &mut Shell, pgid: Option<Pid>, foreground: bool) -> io::Result<Pid> {
+    io::stdout().flush().ok();
+    let pid = sys::fork()?;
+    if pid == 0 {
+        let terminal = shell.terminal.take();
+        if terminal.is_some() || pgid.is_some() {
+            let tty = terminal.map(|t| t.fd).filter(|_| foreground);
+            sys::child_setup(pgid, tty);
+        }
+    } else {
+        join_group(pid, pgid);
+    }
+    Ok(pid)
+}
+
+/// Arrange for a spawned command to start like a child of [`fork_shell`].
+fn setup_process(process: &mut Process, shell: &Shell, pgid: Option<Pid>, foreground: bool) {
+    if shell.terminal.is_none() && pgid.is_none() {
+        return;
+    }
+    let tty = shell.terminal.as_ref().map(|t| t.fd).filter(|_| foreground);
+    unsafe {
+        process.pre_exec(move || {
+            sys::child_setup(pgid, tty);
+            Ok(())
+        });
+    }
+}
+
+/// Run `list` in a forked copy of the shell and capture its standard output,
+/// minus trailing newlines. Sets `$?` to the list's exit status.
+pub fn command_sub(list: &List, shell: &mut Shell) -> String {
+    let (read, write) = match sys::pipe() {
+        Ok(fds) => fds,
+        Err(e) => { eprintln!("rsh: pipe: {}", e); return String::new(); }
+    };
+    let pid = match fork_shell(shell, None, false) {
+        Ok(0) => {
+            sys::close(read);
+            sys::dup2(write, 1).ok();
+            sys::close(write);
+            let code = exec_list(list, shell);
+            sys::exit_child(child_exit_code(shell, code));
+        }
+        Ok(pid) => pid,
+        Err(e) => {
+            eprintln!("rsh: fork: {}", e);
+            sys::close(read);
+            sys::close(write);
+            return String::new();
+        }
+    };
+    sys::close(write);
+
+    let mut bytes = Vec::new();
+    let mut pipe = unsafe { File::from_raw_fd(read) };
+    pipe.read_to_end(&mut bytes).ok();
+    shell.last_exit_code = wait_code(pid);
+
+    let mut output = String::from_utf8_lossy(&bytes).into_owned();
+    output.truncate(output.trim_end_matches('\n').len());
+    output
+}
+
+fn child_exit_code(shell: &Shell, code: i32) -> i32 {
+    match shell.flow {
+        Some(Flow::Exit(c)) => c,
+        _ => code,
+    }
+}
+
+fn wait_code(pid: Pid) -> i32 {
+    sys::wait_pid(pid).map(sys::status_code).unwrap_or(1)
+}
+
+fn spawn_single(argv: &[String], env: &[(String, String)], cmd: &SimpleCmd, shell: &mut Shell) -> i32 {
+    let pgid = job_pgid(shell);
+    // The redirections only need to last until the child has its copies.
+    let spawned = match redirect_shell(&cmd.redirects, shell) {
+        Ok(_saved) if argv.is_empty() => return 0,
+        Ok(_saved) => spawn_external(argv, env, pgid, shell),
+        Err(code) => return code,
+    };
+    match spawned {
+        Ok(pid) => jobs::wait_foreground(shell, Job::new(pgid, vec![pid], cmd.to_string())),
+        Err(code) => code,
+    }
+}
+
+/// An external command with the shell's current descriptors. `env` adds
+/// to the exported variables.
+fn external(argv: &[String], env: &[(String, String)], shell: &Shell) -> Result<Process, i32> {
+    let path = match env.iter().rfind(|(name, _)| name == "PATH") {
+        Some((_, path)) => path,
+        None => shell.vars.get("PATH").unwrap_or_default(),
+    };
+    let program = match builtins::find_in_path(&argv[0], path) {
+        Some(p) => p,
+        None => { eprintln!("{}{}: command not found", shell.location(), argv[0]); return Err(127); }
+    };
+
+    let mut process = Process::new(&program);
+    process.args(&argv[1..]).env_clear().envs(shell.vars.environment()).envs(env.iter().map(|(k, v)| (k, v)));
+    Ok(process)
+}
+
+/// Replace the shell process with an external command. Only returns, with
+/// a status, if that fails.
+fn exec_external(argv: &[String], env: &[(String, String)], shell: &Shell) -> i32 {
+    match external(argv, env, shell) {
+        Ok(mut process) => {
+            let e = process.exec();
+            eprintln!("{}{}: {}", shell.location(), argv[0], e);
+            1
+        }
+        Err(code) => code,
+    }
+}
+
+/// Start an external command and put it in process group `pgid`.
+fn spawn_external(argv: &[String], env: &[(String, String)], pgid: Option<Pid>, shell: &Shell) -> Result<Pid, i32> {
+    let mut process = external(argv, env, shell)?;
+    setup_process(&mut process, shell, pgid, true);
+    match process.spawn() {
+        Ok(child) => {
+            let pid = child.id() as Pid;
+            join_group(pid, pgid);
+            Ok(pid)
+        }
+        Err(e) => { eprintln!("{}{}: {}", shell.location(), argv[0], e); Err(1) }
+    }
+}
+
+/// Run a multi-command pipeline, each stage in its own process and all of
+/// them in one process group.
+fn run_pipe_chain(cmds: &[Command], shell: &mut Shell) -> i32 {
+    let last = cmds.len() - 1;
+    let mut prev_read: Option<i32> = None;
+    // Each stage's process, or its status if it could not be started.
+    let mut stages: Vec<Result<Pid, i32>> = Vec::new();
+    let mut pids: Vec<Pid> = Vec::new();
+    let mut pgid = job_pgid(shell);
+
+    for (i, cmd) in cmds.iter().enumerate() {
+        let is_last = i == last;
+        let (next_read, write) = if is_last {
+            (None, None)
         } else {
-            Stdio::piped()
+            match sys::pipe() {
+                Ok((r, w)) => (Some(r), Some(w)),
+                Err(e) => {
+                    eprintln!("rsh: pipe: {}", e);
+                    if let Some(fd) = prev_read { sys::close(fd); }
+                    break;
+                }
+            }
         };
 
-        let program = match builtins::find_in_path(&cmd.argv[0]) {
-            Some(p) => p,
-            None => {
-                eprintln!("{}: command not found", cmd.argv[0]);
-                for c in &mut children { c.wait().ok(); }
-                return 127;
+        let stage = fork_stage(cmd, prev_read, write, next_read, pgid, shell);
+        if let Ok(pid) = stage {
+            pids.push(pid);
+            if pgid == Some(0) {
+                pgid = Some(pid);
             }
+        }
+        stages.push(stage);
+        prev_read = next_read;
+    }
+
+    let (job_code, exits) = match pids.first() {
+        Some(_) => {
+            let text = cmds.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(" | ");
+            jobs::wait_pipeline(shell, Job::new(pgid, pids, text))
+        }
+        None => (1, Vec::new()),
+    };
+    let codes: Option<Vec<i32>> = stages
+        .iter()
+        .map(|stage| match stage {
+            Ok(pid) => exits.iter().find(|(p, _)| p == pid).map(|&(_, code)| code),
+            Err(code) => Some(*code),
+        })
+        .collect();
+    // A process that has not exited has stopped, and so has the job.
+    let Some(codes) = codes else {
+        shell.pipe_status = vec![job_code];
+        return job_code;
+    };
+    let code = if shell.options.pipefail {
+        codes.iter().rev().find(|&&code| code != 0).copied().unwrap_or(0)
+    } else {
+        codes.last().copied().unwrap_or(1)
+    };
+    shell.pipe_status = codes;
+    code
+}
+
+/// Fork a copy of the shell to run one stage of a pipeline. The stage's
+/// words are expanded and its redirections applied in the child, after the
+/// pipe ends are in place, so that nothing it does changes the shell.
+fn fork_stage(cmd: &Command, stdin: Option<i32>, stdout: Option<i32>, unused: Option<i32>, pgid: Option<Pid>, shell: &mut Shell) -> Result<Pid, i32> {
+    let result = fork_shell(shell, pgid, true);
+    if let Ok(0) = result {
+        if let Some(fd) = unused { sys::close(fd); }
+        for (fd, target) in [(stdin, 0), (stdout, 1)] {
+            if let Some(fd) = fd {
+                sys::dup2(fd, target).ok();
+                sys::close(fd);
+            }
+        }
+        let code = match cmd {
+            Command::Simple(simple) => run_simple(simple, true, shell),
+            _ => run_command(cmd, shell),
         };
+        sys::exit_child(child_exit_code(shell, code));
+    }
+    for fd in [stdin, stdout].into_iter().flatten() {
+        sys::close(fd);
+    }
+    result.map_err(|e| { eprintln!("rsh: fork: {}", e); 1 })
+}
+
+/// File descriptors replaced by redirections on a command that runs inside
+/// the shell itself, or while a child is started. Dropping this restores
+/// the originals.
+struct SavedFds {
+    /// Each changed descriptor with a copy of what it was, or `None` if it
+    /// was closed.
+    saved: Vec<(i32, Option<i32>)>,
+}
+
+impl SavedFds {
+    fn new() -> Self {
+        io::stdout().flush().ok();
+        SavedFds { saved: Vec::new() }
+    }
+
+    /// Keep a copy of `fd` before it is first changed.
+    fn save(&mut self, fd: i32) {
+        if !self.saved.iter().any(|&(s, _)| s == fd) {
+            self.saved.push((fd, sys::dup(fd).ok()));
+        }
+    }
+
+    /// Make `fd` refer to what `src` refers to.
+    fn dup_to(&mut self, src: i32, fd: i32) -> io::Result<()> {
+        self.save(fd);
+        sys::dup2(src, fd)
+    }
+
+    fn close(&mut self, fd: i32) {
+        self.save(fd);
+        sys::close(fd);
+    }
+}
 
-        match Command::new(&program)
-            .args(&cmd.argv[1..])
-            .stdin(stdin)
-            .stdout(stdout)
-            .spawn()
-        {
-            Ok(mut child) => {
-                if !is_last {
-                    prev_stdout = child.stdout.take();
+impl Drop for SavedFds {
+    fn drop(&mut self) {
+        io::stdout().flush().ok();
+        for &(fd, copy) in self.saved.iter().rev() {
+            match copy {
+                Some(copy) => {
+                    sys::dup2(copy, fd).ok();
+                    sys::close(copy);
                 }
-                children.push(child);
-            }
-            Err(e) => {
-                eprintln!("{}: {}", cmd.argv[0], e);
-                for c in &mut children { c.wait().ok(); }
-                return 1;
+                None => sys::close(fd),
             }
         }
     }
+}
 
-    let last_idx = children.len().saturating_sub(1);
-    let mut last_code = 0;
-    for (i, child) in children.iter_mut().enumerate() {
-        if let Ok(status) = child.wait() {
-            if i == last_idx {
-                last_code = status.code().unwrap_or(0);
-            }
+/// Apply redirections to the shell's own descriptors, in order.
+fn redirect_shell(redirects: &[Redirect], shell: &mut Shell) -> Result<SavedFds, i32> {
+    let mut saved = SavedFds::new();
+    for redirect in redirects {
+        if let Err(message) = apply_redirect(redirect, &mut saved, shell) {
+            eprintln!("{}{}", shell.error_prefix(), message);
+            return Err(1);
         }
     }
-    last_code
+    Ok(saved)
 }
 
-fn make_stdin(file: &Option<String>) -> Stdio {
-    match file {
-        None => Stdio::inherit(),
-        Some(f) => match File::open(f) {
-            Ok(file) => Stdio::from(file),
-            Err(e) => { eprintln!("{}: {}", f, e); Stdio::inherit() }
+fn apply_redirect(redirect: &Redirect, saved: &mut SavedFds, shell: &mut Shell) -> Result<(), String> {
+    let fd = redirect.source_fd();
+    let text = match redirect.kind {
+        RedirectKind::HereDoc => redirect.here_doc.as_ref().map(|body| expand_word(body, shell)),
+        RedirectKind::HereString => Some(expand_word(&redirect.target, shell).map(|text| text + "\n")),
+        _ => None,
+    };
+    let text = text.transpose().map_err(|e| e.to_string())?;
+    if let Some(text) = text {
+        saved.save(fd);
+        let raw = here_file(&text).map_err(|e| format!("here-document: {}", e))?.into_raw_fd();
+        let result = saved.dup_to(raw, fd);
+        if raw != fd {
+            sys::close(raw);
         }
+        return result.map_err(|e| e.to_string());
     }
+
+    let target = expand_word(&redirect.target, shell).map_err(|e| e.to_string())?;
+    let mut both = matches!(redirect.kind, RedirectKind::OutErr | RedirectKind::AppendErr);
+
+    if matches!(redirect.kind, RedirectKind::DupIn | RedirectKind::DupOut) {
+        if target == "-" {
+            saved.close(fd);
+            return Ok(());
+        }
+        match target.parse::<i32>() {
+            Ok(src) => return saved.dup_to(src, fd).map_err(|e| format!("{}: {}", src, e)),
+            // `>&file` is another way to write `&>file`.
+            Err(_) if redirect.kind == RedirectKind::DupOut && redirect.fd.is_none() => both = true,
+            Err(_) => return Err(format!("{}: ambiguous redirect", target)),
+        }
+    }
+
+    // Saved first: the file may be opened on `fd` itself if it was closed.
+    saved.save(fd);
+    let file = match redirect.kind {
+        RedirectKind::In => File::open(&target),
+        RedirectKind::ReadWrite => OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&target),
+        RedirectKind::Append | RedirectKind::AppendErr => open_out(&target, true, false),
+        RedirectKind::Clobber => open_out(&target, false, false),
+        _ => open_out(&target, false, shell.options.noclobber),
+    };
+    let raw = file.map_err(|e| format!("{}: {}", target, e))?.into_raw_fd();
+    let result = saved.dup_to(raw, fd);
+    if raw != fd {
+        sys::close(raw);
+    }
+    result.map_err(|e| e.to_string())?;
+    if both {
+        saved.dup_to(fd, 2).map_err(|e| e.to_string())?;
+    }
+    Ok(())
 }
 
-fn make_stdout(file: &Option<String>, append: bool) -> Stdio {
-    match file {
-        None => Stdio::inherit(),
-        Some(f) => {
-            let result = if append {
-                OpenOptions::new().create(true).append(true).open(f)
-            } else {
-                OpenOptions::new().create(true).write(true).truncate(true).open(f)
-            };
-            match result {
-                Ok(file) => Stdio::from(file),
-                Err(e) => { eprintln!("{}: {}", f, e); Stdio::inherit() }
+/// An unlinked temporary file holding `text`, positioned at its start,
+/// to be read as a here-document.
+fn here_file(text: &str) -> io::Result<File> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNT.fetch_add(1, Ordering::Relaxed);
+    let path = env::temp_dir().join(format!("rsh-{}-{}", sys::getpid(), n));
+    let mut file = OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+    fs::remove_file(&path)?;
+    file.write_all(text.as_bytes())?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Open a file for `>` or `>>`. With `noclobber`, an existing regular
+/// file is not truncated.
+fn open_out(path: &str, append: bool, noclobber: bool) -> io::Result<File> {
+    if append {
+        OpenOptions::new().create(true).append(true).open(path)
+    } else if noclobber {
+        // Creating exclusively leaves no window for another process to
+        // make the file between the check and the open.
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if fs::metadata(path).is_ok_and(|meta| meta.is_file()) {
+                    Err(io::Error::other("cannot overwrite existing file"))
+                } else {
+                    OpenOptions::new().write(true).open(path)
+                }
             }
+            result => result,
         }
+    } else {
+        OpenOptions::new().create(true).write(true).truncate(true).open(path)
     }
 }
@@ -0,0 +1,596 @@
+use std::fmt;
+
+use crate::arith;
+use crate::brace;
+use crate::executor;
+use crate::glob;
+use crate::lexer::{ParamExp, ParamOp, Word, WordPart};
+use crate::parser;
+use crate::shell::{Flow, Shell};
+
+/// An expansion that failed; the command it belongs to is not run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpandError {
+    pub message: String,
+}
+
+impl fmt::Display for ExpandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Expand braces in every word, expand the results into fields, split
+/// unquoted expansions on `$IFS`, then expand pathnames. Fields that
+/// expand to nothing are dropped unless they were quoted, as in `""`.
+pub fn expand_words(words: &[Word], shell: &mut Shell) -> Result<Vec<String>, ExpandError> {
+    let ifs = shell.vars.get("IFS").unwrap_or(" \t\n").to_string();
+    let mut out = Vec::new();
+    for word in words.iter().flat_map(brace::expand) {
+        let mut fields = Fields { ifs: Some(ifs.clone()), ..Fields::default() };
+        expand_parts(&word.parts, false, shell, &mut fields)?;
+        for field in fields.finish() {
+            if field.text.is_empty() {
+                if field.quoted {
+                    out.push(field.text);
+                }
+                continue;
+            }
+            if !field.glob || shell.options.noglob {
+                out.push(field.text);
+                continue;
+            }
+            let matches = glob::expand(&field.pattern, shell.shopts.dotglob);
+            if !matches.is_empty() {
+                out.extend(matches);
+            } else if shell.shopts.failglob {
+                return Err(ExpandError { message: format!("no match: {}", field.text) });
+            } else if !shell.shopts.nullglob {
+                out.push(field.text);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Expand a single word into one string, without field splitting or
+/// pathname expansion.
+pub fn expand_word(word: &Word, shell: &mut Shell) -> Result<String, ExpandError> {
+    let mut fields = Fields::default();
+    expand_parts(&word.parts, false, shell, &mut fields)?;
+    Ok(fields.finish().into_iter().map(|f| f.text).collect())
+}
+
+/// Expand a word into a pattern for `case`, in which quoted characters
+/// only match themselves.
+pub fn expand_pattern(word: &Word, shell: &mut Shell) -> Result<String, ExpandError> {
+    let mut fields = Fields::default();
+    expand_parts(&word.parts, false, shell, &mut fields)?;
+    Ok(fields.finish().into_iter().map(|f| f.pattern).collect())
+}
+
+/// Expand an arithmetic expression and evaluate it.
+pub fn expand_arith(expr: &Word, shell: &mut Shell) -> Result<i64, ExpandError> {
+    let text = expand_word(expr, shell)?;
+    arith::eval(&text, &mut shell.vars).map_err(|message| ExpandError { message })
+}
+
+/// One field of an expanded word.
+#[derive(Default)]
+struct Field {
+    text: String,
+    /// The text as a glob pattern, with quoted characters escaped.
+    pattern: String,
+    /// Whether the field contains an unquoted glob character.
+    glob: bool,
+    /// Whether the field is kept even if it is empty.
+    quoted: bool,
+}
+
+impl Field {
+    fn push(&mut self, c: char, quoted: bool) {
+        self.text.push(c);
+        if matches!(c, '*' | '?' | '[') && !quoted {
+            self.glob = true;
+        }
+        if (quoted && matches!(c, '*' | '?' | '[' | ']')) || c == '\\' {
+            self.pattern.push('\\');
+        }
+        self.pattern.push(c);
+    }
+}
+
+/// The fields produced so far while expanding one word.
+#[derive(Default)]
+struct Fields {
+    done: Vec<Field>,
+    current: Field,
+    /// The characters unquoted expansions are split on, if they are split
+    /// into fields at all.
+    ifs: Option<String>,
+}
+
+impl Fields {
+    fn push_str(&mut self, text: &str, quoted: bool) {
+        for c in text.chars() {
+            self.current.push(c, quoted);
+        }
+    }
+
+    /// Append the result of an expansion, splitting it on `$IFS` if it was
+    /// unquoted. Runs of IFS whitespace separate fields; every other IFS
+    /// character ends one, even an empty one.
+    fn push_expansion(&mut self, text: &str, quoted: bool) {
+        let Some(ifs) = self.ifs.take().filter(|_| !quoted) else {
+            self.push_str(text, quoted);
+            return;
+        };
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            if !ifs.contains(c) {
+                self.current.push(c, false);
+                continue;
+            }
+            let mut hard = !c.is_whitespace();
+            while let Some(&next) = chars.peek().filter(|&&n| ifs.contains(n)) {
+                if !next.is_whitespace() {
+                    if hard {
+                        break;
+                    }
+                    hard = true;
+                }
+                chars.next();
+            }
+            if hard || !self.current.text.is_empty() || self.current.quoted {
+                self.current.quoted = true;
+                self.next_field();
+            }
+        }
+        self.ifs = Some(ifs);
+    }
+
+    /// Append expansions that are each a field of their own, as for `$@`.
+    /// Unquoted, they are joined with the first `$IFS` character and split
+    /// again, so that an empty one still makes a field if that character
+    /// is not whitespace.
+    fn push_each(&mut self, values: &[String], quoted: bool) {
+        if let Some(sep) = self.ifs.as_deref().and_then(|ifs| ifs.chars().next()).filter(|_| !quoted) {
+            self.push_expansion(&values.join(sep.encode_utf8(&mut [0; 4])), false);
+            return;
+        }
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                self.next_field();
+            }
+            self.current.quoted |= quoted;
+            self.push_expansion(value, quoted);
+        }
+    }
+
+    fn next_field(&mut self) {
+        self.done.push(std::mem::take(&mut self.current));
+    }
+
+    fn finish(mut self) -> Vec<Field> {
+        self.done.push(self.current);
+        self.done
+    }
+}
+
+fn expand_parts(parts: &[WordPart], quoted: bool, shell: &mut Shell, fields: &mut Fields) -> Result<(), ExpandError> {
+    for part in parts {
+        match part {
+            WordPart::Literal(s) => fields.push_str(s, quoted),
+            WordPart::Quoted(s) => {
+                fields.current.quoted = true;
+                fields.push_str(s, true);
+            }
+            // `"$@"` with no parameters is no field at all.
+            WordPart::DoubleQuoted(inner) if matches!(inner.as_slice(), [WordPart::Param(name)] if name == "@") => {
+                expand_parts(inner, true, shell, fields)?
+            }
+            WordPart::DoubleQuoted(inner) => {
+                fields.current.quoted = true;
+                expand_parts(inner, true, shell, fields)?
+            }
+            // Each positional parameter is a field of its own.
+            WordPart::Param(name) if name == "@" || (name == "*" && !quoted) => {
+                fields.push_each(&shell.positional, quoted)
+            }
+            // So is each element of `${name[@]}`.
+            WordPart::ParamExp(exp)
+                if exp.op == ParamOp::Value
+                    && !exp.indirect
+                    && exp.index.as_ref().and_then(Word::as_literal) == Some("@") =>
+            {
+                fields.push_each(&elements(&exp.name, shell), quoted)
+            }
+            // And each parameter in a slice of them, as in `"${@:2}"`.
+            WordPart::ParamExp(exp) if exp.name == "@" || (exp.name == "*" && !quoted) => {
+                let ParamOp::Substring { offset, length } = &exp.op else {
+                    let value = param_exp(exp, shell).map_err(|e| fatal(e, 1, shell))?;
+                    fields.push_expansion(&value, quoted);
+                    continue;
+                };
+                let slice = positional_slice(offset, length.as_ref(), shell).map_err(|e| fatal(e, 1, shell))?;
+                fields.push_each(&slice, quoted);
+            }
+            WordPart::Param(name) => {
+                let value = param(name, shell).map_err(|e| fatal(e, 1, shell))?;
+                fields.push_expansion(&value, quoted)
+            }
+            WordPart::ParamExp(exp) => {
+                let value = param_exp(exp, shell).map_err(|e| fatal(e, 1, shell))?;
+                fields.push_expansion(&value, quoted)
+            }
+            WordPart::Arith(expr) => {
+                let value = expand_arith(expr, shell).map_err(|e| fatal(e, 1, shell))?;
+                fields.push_expansion(&value.to_string(), quoted)
+            }
+            WordPart::CommandSub(list) => fields.push_expansion(&executor::command_sub(list, shell), quoted),
+            WordPart::Tilde(user) => fields.push_str(&tilde(user, shell), true),
+        }
+    }
+    Ok(())
+}
+
+fn param(name: &str, shell: &mut Shell) -> Result<String, ExpandError> {
+    match lookup(name, shell) {
+        Some(value) => Ok(value),
+        None if shell.options.nounset && !matches!(name, "@" | "*") => Err(unbound(name, shell)),
+        None => Ok(String::new()),
+    }
+}
+
+/// A failed parameter or arithmetic expansion ends a shell that is not
+/// interactive, with `status` unless an earlier error chose one.
+fn fatal(error: ExpandError, status: i32, shell: &mut Shell) -> ExpandError {
+    if !shell.interactive && shell.flow.is_none() {
+        shell.flow = Some(Flow::Exit(status));
+    }
+    error
+}
+
+/// The `set -u` error for an unset parameter.
+fn unbound(name: &str, shell: &mut Shell) -> ExpandError {
+    fatal(ExpandError { message: format!("{}: unbound variable", name) }, 127, shell)
+}
+
+/// A parameter's value, or `None` if it is unset.
+fn lookup(name: &str, shell: &Shell) -> Option<String> {
+    match name {
+        "?" => Some(shell.last_exit_code.to_string()),
+        "!" => shell.last_bg_pid.map(|p| p.to_string()),
+        "#" => Some(shell.positional.len().to_string()),
+        "@" | "*" if shell.positional.is_empty() => None,
+        "@" => Some(shell.positional.join(" ")),
+        "*" => Some(shell.positional.join(&separator(shell))),
+        "$" => Some(shell.pid.to_string()),
+        "0" => Some(shell.arg0.clone()),
+        "-" => Some(shell.option_flags()),
+        "_" => Some(shell.last_arg.clone()),
+        "PIPESTATUS" => shell.pipe_status.first().map(|code| code.to_string()),
+        _ if name.bytes().all(|b| b.is_ascii_digit()) => {
+            let n: usize = name.parse().unwrap_or(0);
+            n.checked_sub(1).and_then(|i| shell.positional.get(i)).cloned()
+        }
+        _ => shell.vars.get(name).map(str::to_string),
+    }
+}
+
+/// `${@:offset:length}`: the positional parameters from `offset` on,
+/// where 0 is `$0`. A negative offset counts back from the end.
+fn positional_slice(offset: &Word, length: Option<&Word>, shell: &mut Shell) -> Result<Vec<String>, ExpandError> {
+    let params: Vec<String> = std::iter::once(shell.arg0.clone()).chain(shell.positional.iter().cloned()).collect();
+    let len = params.len() as i64;
+    let offset = expand_arith(offset, shell)?;
+    let start = if offset < 0 { len + offset } else { offset };
+    let count = match length {
+        None => len,
+        Some(length) => match expand_arith(length, shell)? {
+            n if n < 0 => return Err(ExpandError { message: format!("{}: substring expression < 0", n) }),
+            n => n,
+        },
+    };
+    if start < 0 || start >= len {
+        return Ok(Vec::new());
+    }
+    let end = start.saturating_add(count).min(len);
+    Ok(params[start as usize..end as usize].to_vec())
+}
+
+/// What `"$*"` and `"${name[*]}"` join values with: the first character
+/// of `$IFS`.
+fn separator(shell: &Shell) -> String {
+    match shell.vars.get("IFS") {
+        Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+        None => " ".to_string(),
+    }
+}
+
+/// The elements of an array: the statuses in `PIPESTATUS`, or for any
+/// other parameter its value if it is set.
+fn elements(name: &str, shell: &Shell) -> Vec<String> {
+    match name {
+        "PIPESTATUS" => shell.pipe_status.iter().map(|code| code.to_string()).collect(),
+        _ => lookup(name, shell).into_iter().collect(),
+    }
+}
+
+/// Expand `${...}` with an operator.
+fn param_exp(exp: &ParamExp, shell: &mut Shell) -> Result<String, ExpandError> {
+    let name = if exp.indirect { param(&exp.name, shell)? } else { exp.name.clone() };
+    if exp.indirect && !parser::is_name(&name) && name.parse::<usize>().is_err() {
+        return Err(ExpandError { message: format!("{}: invalid indirect expansion", exp.name) });
+    }
+    let index = match &exp.index {
+        Some(index) => Some(expand_word(index, shell)?),
+        None => None,
+    };
+    let all = matches!(index.as_deref(), Some("@" | "*"));
+    let value = match index.as_deref() {
+        None => lookup(&name, shell),
+        Some(index) => {
+            let elements = elements(&name, shell);
+            match index {
+                _ if elements.is_empty() => None,
+                "@" => Some(elements.join(" ")),
+                "*" => Some(elements.join(&separator(shell))),
+                _ => {
+                    let i = arith::eval(index, &mut shell.vars).map_err(|message| ExpandError { message })?;
+                    let i = if i < 0 { elements.len() as i64 + i } else { i };
+                    usize::try_from(i).ok().and_then(|i| elements.get(i)).cloned()
+                }
+            }
+        }
+    };
+    // The `-`, `=`, `?` and `+` operators are how to test for unset ones.
+    let defaulted = matches!(exp.op, ParamOp::Default { .. }) || matches!(name.as_str(), "@" | "*") || all;
+    if value.is_none() && shell.options.nounset && !defaulted {
+        return Err(unbound(&name, shell));
+    }
+    Ok(match &exp.op {
+        ParamOp::Value => value.unwrap_or_default(),
+        ParamOp::Length if matches!(name.as_str(), "@" | "*") => shell.positional.len().to_string(),
+        ParamOp::Length if all => elements(&name, shell).len().to_string(),
+        ParamOp::Length => value.map_or(0, |v| v.chars().count()).to_string(),
+        ParamOp::Default { op, colon, word } => {
+            let missing = value.as_ref().is_none_or(|v| *colon && v.is_empty());
+            match (op, missing) {
+                ('+', true) => String::new(),
+                ('+', false) => expand_word(word, shell)?,
+                (_, false) => value.unwrap_or_default(),
+                ('=', true) => {
+                    let value = expand_word(word, shell)?;
+                    if !parser::is_name(&name) {
+                        return Err(ExpandError { message: format!("${}: cannot assign in this way", name) });
+                    }
+                    shell.vars.set(&name, value.clone()).map_err(|message| ExpandError { message })?;
+                    value
+                }
+                ('?', true) => {
+                    let message = expand_word(word, shell)?;
+                    let message = if message.is_empty() { "parameter null or not set".to_string() } else { message };
+                    return Err(fatal(ExpandError { message: format!("{}: {}", name, message) }, 127, shell));
+                }
+                _ => expand_word(word, shell)?,
+            }
+        }
+        ParamOp::Remove { suffix, longest, pattern } => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(pattern, shell)?;
+            remove_match(&value, &pattern, *suffix, *longest).to_string()
+        }
+        ParamOp::Replace { all, pattern, with } => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(pattern, shell)?;
+            let with = expand_word(with, shell)?;
+            replace_matches(&value, &pattern, &with, *all)
+        }
+        ParamOp::Substring { offset, length } if matches!(name.as_str(), "@" | "*") => {
+            let slice = positional_slice(offset, length.as_ref(), shell)?;
+            slice.join(&if name == "*" { separator(shell) } else { " ".to_string() })
+        }
+        ParamOp::Substring { offset, length } => {
+            let value = value.unwrap_or_default();
+            let chars: Vec<char> = value.chars().collect();
+            let len = chars.len() as i64;
+            let offset = expand_arith(offset, shell)?;
+            let start = if offset < 0 { (len + offset).max(0) } else { offset.min(len) };
+            let end = match length {
+                None => len,
+                Some(length) => match expand_arith(length, shell)? {
+                    n if n < 0 => len + n,
+                    n => start.saturating_add(n).min(len),
+                },
+            };
+            if end < start {
+                return Err(ExpandError { message: format!("{}: substring expression < 0", name) });
+            }
+            chars[start as usize..end as usize].iter().collect()
+        }
+        ParamOp::Case { upper, all, pattern } => {
+            let value = value.unwrap_or_default();
+            let pattern = expand_pattern(pattern, shell)?;
+            let pattern = if pattern.is_empty() { "?" } else { pattern.as_str() };
+            let mut out = String::new();
+            for (i, c) in value.chars().enumerate() {
+                if (i == 0 || *all) && glob::matches(pattern, c.encode_utf8(&mut [0; 4])) {
+                    if *upper {
+                        out.extend(c.to_uppercase());
+                    } else {
+                        out.extend(c.to_lowercase());
+                    }
+                } else {
+                    out.push(c);
+                }
+            }
+            out
+        }
+    })
+}
+
+/// `value` without the shortest or longest prefix or suffix that matches
+/// `pattern`.
+fn remove_match<'a>(value: &'a str, pattern: &str, suffix: bool, longest: bool) -> &'a str {
+    let mut cuts: Vec<usize> = value.char_indices().map(|(i, _)| i).chain([value.len()]).collect();
+    if suffix != longest {
+        cuts.reverse();
+    }
+    for cut in cuts {
+        if suffix && glob::matches(pattern, &value[cut..]) {
+            return &value[..cut];
+        }
+        if !suffix && glob::matches(pattern, &value[..cut]) {
+            return &value[cut..];
+        }
+    }
+    value
+}
+
+/// Replace the longest match of `pattern` in `value`, or every match if
+/// `all`. A pattern starting with `#` or `%` must match at the start or
+/// the end.
+fn replace_matches(value: &str, pattern: &str, with: &str, all: bool) -> String {
+    let (pattern, anchor) = match pattern.chars().next() {
+        Some(c @ ('#' | '%')) => (&pattern[1..], Some(c)),
+        _ => (pattern, None),
+    };
+    if pattern.is_empty() && anchor.is_none() {
+        return value.to_string();
+    }
+    let bounds: Vec<usize> = value.char_indices().map(|(i, _)| i).chain([value.len()]).collect();
+    let mut out = String::new();
+    let mut at = 0;
+    while at < bounds.len() {
+        let start = bounds[at];
+        let found = match anchor {
+            Some('#') if start != 0 => None,
+            Some('%') => glob::matches(pattern, &value[start..]).then_some(bounds.len() - 1),
+            _ => (at..bounds.len()).rev().find(|&end| glob::matches(pattern, &value[start..bounds[end]])),
+        };
+        match found {
+            // An empty match only counts when anchored.
+            Some(end) if end > at || anchor.is_some() => {
+                out.push_str(with);
+                if !all || anchor.is_some() {
+                    out.push_str(&value[bounds[end]..]);
+                    return out;
+                }
+                at = end;
+            }
+            _ => {
+                if at + 1 < bounds.len() {
+                    out.push_str(&value[start..bounds[at + 1]]);
+                }
+                at += 1;
+            }
+        }
+    }
+    out
+}
+
+fn tilde(user: &str, shell: &Shell) -> String {
+    if user.is_empty() {
+        shell.vars.get("HOME").map_or_else(|| "~".to_string(), str::to_string)
+    } else {
+        format!("~{}", user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{self, Token};
+
+    /// A shell with `$IFS` set, or unset if `None`, and the positional
+    /// parameters `args`.
+    fn shell(ifs: Option<&str>, args: &[&str]) -> Shell {
+        let mut shell = Shell::new();
+        match ifs {
+            Some(ifs) => shell.vars.set("IFS", ifs).unwrap(),
+            None => shell.vars.unset("IFS").unwrap(),
+        }
+        shell.positional = args.iter().map(|arg| arg.to_string()).collect();
+        shell
+    }
+
+    /// The fields the words of `source` expand to.
+    fn fields(source: &str, shell: &mut Shell) -> Vec<String> {
+        let words: Vec<Word> = lexer::tokenize(source)
+            .unwrap()
+            .into_iter()
+            .map(|(tok, _)| match tok {
+                Token::Word(word) => word,
+                other => panic!("not a word: {:?}", other),
+            })
+            .collect();
+        expand_words(&words, shell).unwrap()
+    }
+
+    #[test]
+    fn unquoted_expansions_are_split() {
+        let mut shell = shell(None, &[]);
+        shell.vars.set("v", "  a  b\tc\n ").unwrap();
+        assert_eq!(fields("$v", &mut shell), ["a", "b", "c"]);
+        assert_eq!(fields("x$v", &mut shell), ["x", "a", "b", "c"]);
+        assert_eq!(fields("\"$v\"", &mut shell), ["  a  b\tc\n "]);
+        assert_eq!(fields("\"x\"$v\"y\"", &mut shell), ["x", "a", "b", "c", "y"]);
+        // Literal text is never split.
+        assert_eq!(fields("a\\ b 'c d'", &mut shell), ["a b", "c d"]);
+    }
+
+    #[test]
+    fn empty_fields() {
+        let mut shell = shell(None, &[]);
+        shell.vars.set("e", "").unwrap();
+        shell.vars.set("s", "   ").unwrap();
+        assert_eq!(fields("$e $s ${unset}", &mut shell), Vec::<String>::new());
+        assert_eq!(fields("\"$e\" '' \"\"", &mut shell), ["", "", ""]);
+        assert_eq!(fields("$e\"\"", &mut shell), [""]);
+        assert_eq!(fields("${e}x", &mut shell), ["x"]);
+    }
+
+    #[test]
+    fn positional_parameters() {
+        let mut shell = shell(None, &["x y", "", "z"]);
+        assert_eq!(fields("\"$@\"", &mut shell), ["x y", "", "z"]);
+        assert_eq!(fields("\"a$@b\"", &mut shell), ["ax y", "", "zb"]);
+        assert_eq!(fields("$@", &mut shell), ["x", "y", "z"]);
+        assert_eq!(fields("$*", &mut shell), ["x", "y", "z"]);
+        assert_eq!(fields("\"$*\"", &mut shell), ["x y  z"]);
+
+        let mut shell = self::shell(None, &[]);
+        assert_eq!(fields("\"$@\"", &mut shell), Vec::<String>::new());
+        assert_eq!(fields("\"$*\"", &mut shell), [""]);
+        assert_eq!(fields("x\"$@\"", &mut shell), ["x"]);
+    }
+
+    #[test]
+    fn non_whitespace_ifs() {
+        let mut shell = shell(Some(":"), &["x y", "", "z"]);
+        shell.vars.set("v", "a::b:").unwrap();
+        // Each `:` ends a field, even an empty one; a trailing one does
+        // not start another.
+        assert_eq!(fields("$v", &mut shell), ["a", "", "b"]);
+        assert_eq!(fields("\"$*\"", &mut shell), ["x y::z"]);
+        assert_eq!(fields("$*", &mut shell), ["x y", "", "z"]);
+        assert_eq!(fields("$@", &mut shell), ["x y", "", "z"]);
+
+        // Whitespace around a non-whitespace separator belongs to it.
+        let mut shell = self::shell(Some(": "), &[]);
+        shell.vars.set("v", " a : b  ::c ").unwrap();
+        assert_eq!(fields("$v", &mut shell), ["a", "b", "", "c"]);
+    }
+
+    #[test]
+    fn empty_ifs() {
+        let mut shell = shell(Some(""), &["x y", "", "z"]);
+        shell.vars.set("v", "a b:c").unwrap();
+        assert_eq!(fields("$v", &mut shell), ["a b:c"]);
+        assert_eq!(fields("\"$*\"", &mut shell), ["x yz"]);
+        // The parameters stay separate, but unquoted empty ones go.
+        assert_eq!(fields("$*", &mut shell), ["x y", "z"]);
+        assert_eq!(fields("\"$@\"", &mut shell), ["x y", "", "z"]);
+    }
+}
@@ -0,0 +1,317 @@
+use std::fs;
+use std::path::Path;
+
+/// True if the pattern contains an unquoted `*`, `?` or `[`.
+pub fn has_magic(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => { chars.next(); }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Remove the backslashes that quote characters in a pattern.
+pub fn unescape(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(nc) = chars.next() {
+                out.push(nc);
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// Match a whole string against a shell pattern. A backslash in the pattern
+/// makes the next character literal.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the most recent `*`: (pattern index, text index).
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match bracket(&pattern[p..], text[t]) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                None => (text[t] == '[').then_some(1),
+            },
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(&c) => (c == text[t]).then_some(1),
+            None => None,
+        };
+        match step {
+            Some(len) => {
+                p += len;
+                t += 1;
+            }
+            None => match star {
+                Some((sp, st)) => {
+                    p = sp;
+                    t = st + 1;
+                    star = Some((sp, st + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Match `c` against the bracket expression at the start of `pattern`.
+/// Returns whether it matched and the length of the expression, or `None`
+/// if the `[` is not closed and so stands for itself.
+fn bracket(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negate = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut lo = *pattern.get(i)?;
+        if lo == ']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+        if lo == '[' && pattern.get(i + 1) == Some(&':') {
+            let rest: String = pattern[i + 2..].iter().collect();
+            if let Some(end) = rest.find(":]") {
+                let class = &rest[..end];
+                matched |= class_matches(class, c);
+                i += 2 + class.chars().count() + 2;
+                continue;
+            }
+        }
+        if lo == '\\' {
+            i += 1;
+            lo = *pattern.get(i)?;
+        }
+        i += 1;
+        if pattern.get(i) == Some(&'-') && pattern.get(i + 1).is_some_and(|&h| h != ']') {
+            let mut hi = pattern[i + 1];
+            i += 2;
+            if hi == '\\' {
+                hi = *pattern.get(i)?;
+                i += 1;
+            }
+            matched |= lo <= c && c <= hi;
+        } else {
+            matched |= lo == c;
+        }
+    }
+}
+
+fn class_matches(class: &str, c: char) -> bool {
+    match class {
+        "alnum" => c.is_alphanumeric(),
+        "alpha" => c.is_alphabetic(),
+        "blank" => c == ' ' || c == '\t',
+        "cntrl" => c.is_control(),
+        "digit" => c.is_ascii_digit(),
+        "graph" => !c.is_whitespace() && !c.is_control(),
+        "lower" => c.is_lowercase(),
+        "print" => !c.is_control(),
+        "punct" => c.is_ascii_punctuation(),
+        "space" => c.is_whitespace(),
+        "upper" => c.is_uppercase(),
+        "xdigit" => c.is_ascii_hexdigit(),
+        _ => false,
+    }
+}
+
+/// Expand a pattern into the sorted list of paths it matches. `**` as a
+/// whole component matches any number of directories. Names starting with
+/// `.` only match a pattern that starts with a literal `.`, unless `dotglob`.
+pub fn expand(pattern: &str, dotglob: bool) -> Vec<String> {
+    let (mut paths, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => (vec!["/".to_string()], rest),
+        None => (vec![String::new()], pattern),
+    };
+    let components: Vec<&str> = rest.split('/').collect();
+
+    for (i, component) in components.iter().enumerate() {
+        let is_last = i == components.len() - 1;
+        let mut next = Vec::new();
+        for base in &paths {
+            if component.is_empty() {
+                // A trailing slash only keeps directories.
+                if is_last && is_dir(base) {
+                    next.push(format!("{}/", base.trim_end_matches('/')));
+                }
+            } else if *component == "**" {
+                if is_last {
+                    walk(base, dotglob, false, &mut next);
+                } else {
+                    next.push(base.clone());
+                    walk(base, dotglob, true, &mut next);
+                }
+            } else if has_magic(component) {
+                let hidden = dotglob || component.starts_with('.') || component.starts_with("\\.");
+                let Ok(entries) = fs::read_dir(dir_of(base)) else { continue };
+                for entry in entries.flatten() {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    if (hidden || !name.starts_with('.')) && matches(component, &name) {
+                        next.push(join(base, &name));
+                    }
+                }
+            } else {
+                let path = join(base, &unescape(component));
+                if !is_last || Path::new(&path).symlink_metadata().is_ok() {
+                    next.push(path);
+                }
+            }
+        }
+        paths = next;
+    }
+
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Collect everything below `base`, or only directories if `dirs_only`.
+/// Symlinked directories are listed but not descended into.
+fn walk(base: &str, dotglob: bool, dirs_only: bool, out: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir_of(base)) else { return };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !dotglob && name.starts_with('.') {
+            continue;
+        }
+        let path = join(base, &name);
+        let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+        if is_dir || !dirs_only {
+            out.push(path.clone());
+        }
+        if is_dir {
+            walk(&path, dotglob, dirs_only, out);
+        }
+    }
+}
+
+fn dir_of(base: &str) -> &str {
+    if base.is_empty() { "." } else { base }
+}
+
+fn join(base: &str, name: &str) -> String {
+    match base {
+        "" => name.to_string(),
+        b if b.ends_with('/') => format!("{}{}", b, name),
+        b => format!("{}/{}", b, name),
+    }
+}
+
+fn is_dir(path: &str) -> bool {
+    Path::new(dir_of(path)).is_dir()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_and_question_mark() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "aXXbYc"));
+        assert!(!matches("*.rs", "main.rc"));
+        assert!(matches("?.txt", "a.txt"));
+        assert!(!matches("?.txt", "ab.txt"));
+        assert!(!matches("?", ""));
+    }
+
+    #[test]
+    fn bracket_classes() {
+        assert!(matches("[abc]", "b"));
+        assert!(matches("[a-c]x", "cx"));
+        assert!(!matches("[a-c]", "d"));
+        assert!(matches("[!a-c]", "d"));
+        assert!(!matches("[!a-c]", "b"));
+        assert!(matches("[^a-c]", "z"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[!]]", "a"));
+        assert!(!matches("[!]]", "]"));
+        assert!(matches("[[:digit:]]*", "7up"));
+        assert!(!matches("[[:upper:]]", "a"));
+        // An unclosed bracket is an ordinary character.
+        assert!(matches("[ab", "[ab"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "x"));
+        assert!(!has_magic("a\\*b"));
+        assert!(has_magic("a[bc]"));
+        assert_eq!(unescape("a\\*b"), "a*b");
+    }
+
+    /// A scratch tree under the temp directory, removed when dropped.
+    struct Tree(String);
+
+    impl Tree {
+        fn new(name: &str, files: &[&str]) -> Self {
+            let root = std::env::temp_dir().join(format!("rsh-glob-{}-{}", std::process::id(), name));
+            let root = root.to_string_lossy().into_owned();
+            for file in files {
+                let path = format!("{}/{}", root, file);
+                fs::create_dir_all(Path::new(&path).parent().unwrap()).unwrap();
+                fs::write(&path, "").unwrap();
+            }
+            Tree(root)
+        }
+
+        fn expand(&self, pattern: &str, dotglob: bool) -> Vec<String> {
+            let prefix = format!("{}/", self.0);
+            expand(&format!("{}{}", prefix, pattern), dotglob)
+                .into_iter()
+                .map(|path| path.strip_prefix(&prefix).unwrap().to_string())
+                .collect()
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn dotfiles_are_hidden() {
+        let tree = Tree::new("dot", &[".hidden", "shown", ".rc"]);
+        assert_eq!(tree.expand("*", false), ["shown"]);
+        assert_eq!(tree.expand(".*", false), [".hidden", ".rc"]);
+        assert_eq!(tree.expand("*", true), [".hidden", ".rc", "shown"]);
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        let tree = Tree::new("star", &["a.rs", "src/b.rs", "src/deep/c.rs", "src/deep/d.txt", ".git/e.rs"]);
+        assert_eq!(tree.expand("**/*.rs", false), ["a.rs", "src/b.rs", "src/deep/c.rs"]);
+        assert_eq!(tree.expand("src/**", false), ["src/b.rs", "src/deep", "src/deep/c.rs", "src/deep/d.txt"]);
+        assert_eq!(tree.expand("*/", false), ["src/"]);
+        assert!(tree.expand("*.none", false).is_empty());
+    }
+}
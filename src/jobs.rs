@@ -0,0 +1,344 @@
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
+
+use crate::shell::Shell;
+use crate::sys::{self, Pid, WaitStatus};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    Done(i32),
+}
+
+/// A pipeline that was started in the background or stopped with Ctrl-Z.
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: usize,
+    pub pgid: Pid,
+    /// The job has a process group of its own. Without job control it is
+    /// in the shell's, and `pgid` is just its first process.
+    pub grouped: bool,
+    /// Processes of the job that have not exited yet.
+    pub pids: Vec<Pid>,
+    /// The pipeline's last process; its status is the job's status.
+    pub last_pid: Pid,
+    pub command: String,
+    pub state: JobState,
+    /// Status of `last_pid` once it has exited.
+    code: i32,
+    /// The signal that killed `last_pid`, if any.
+    signal: Option<i32>,
+    /// The status of each process that has exited.
+    exits: Vec<(Pid, i32)>,
+    /// Terminal modes the job had when it stopped, restored by `fg`.
+    modes: Option<libc::termios>,
+    /// The current state has already been reported to the user.
+    notified: bool,
+}
+
+impl Job {
+    /// A job for processes that were just started in process group `pgid`,
+    /// as given to them (0 for one led by the first process), or `None` for
+    /// the shell's. Its id is assigned when it is added to the table.
+    pub fn new(pgid: Option<Pid>, pids: Vec<Pid>, command: String) -> Self {
+        let first = pids.first().copied().unwrap_or(0);
+        let last_pid = pids.last().copied().unwrap_or(first);
+        Job {
+            id: 0,
+            pgid: pgid.filter(|&pgid| pgid != 0).unwrap_or(first),
+            grouped: pgid.is_some(),
+            pids,
+            last_pid,
+            command,
+            state: JobState::Running,
+            code: 0,
+            signal: None,
+            exits: Vec::new(),
+            modes: None,
+            notified: false,
+        }
+    }
+
+    /// Record a state change reported by `waitpid` for one of the job's
+    /// processes.
+    fn update(&mut self, pid: Pid, status: WaitStatus) {
+        match status {
+            WaitStatus::Exited(status) => {
+                self.pids.retain(|&p| p != pid);
+                self.exits.push((pid, sys::status_code(status)));
+                if pid == self.last_pid {
+                    self.code = sys::status_code(status);
+                    self.signal = status.signal();
+                }
+                if self.pids.is_empty() {
+                    self.state = JobState::Done(self.code);
+                    self.notified = false;
+                }
+            }
+            WaitStatus::Stopped(_) => {
+                if self.state != JobState::Stopped {
+                    self.state = JobState::Stopped;
+                    self.notified = false;
+                }
+            }
+            WaitStatus::Continued => self.state = JobState::Running,
+        }
+    }
+}
+
+/// The controlling terminal, held while the shell does job control.
+pub struct Terminal {
+    /// A private duplicate of the terminal descriptor.
+    pub fd: i32,
+    pub shell_pgid: Pid,
+    /// The shell's own terminal modes, restored after each job.
+    pub modes: libc::termios,
+}
+
+impl Terminal {
+    /// Set up job control if standard input is a terminal.
+    pub fn init() -> Option<Terminal> {
+        if !sys::isatty(0) {
+            return None;
+        }
+        let shell_pgid = sys::claim_terminal(0).ok()?;
+        let fd = sys::dup(0).ok()?;
+        let modes = sys::tcgetattr(fd).ok()?;
+        Some(Terminal { fd, shell_pgid, modes })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+}
+
+impl JobTable {
+    /// Add a job, giving it the next free id unless it already has one.
+    /// Returns the job id.
+    pub fn insert(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id = self.jobs.iter().map(|j| j.id).max().unwrap_or(0) + 1;
+        }
+        let id = job.id;
+        let at = self.jobs.iter().position(|j| j.id > id).unwrap_or(self.jobs.len());
+        self.jobs.insert(at, job);
+        id
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Job> {
+        self.jobs.iter().find(|j| j.id == id)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        let at = self.jobs.iter().position(|j| j.id == id)?;
+        Some(self.jobs.remove(at))
+    }
+
+    pub fn ids(&self) -> Vec<usize> {
+        self.jobs.iter().map(|j| j.id).collect()
+    }
+
+    pub fn find_pid(&self, pid: Pid) -> Option<usize> {
+        self.jobs.iter().find(|j| j.pgid == pid || j.pids.contains(&pid)).map(|j| j.id)
+    }
+
+    /// The jobs `%+` and `%-` refer to: the most recently stopped jobs
+    /// first, then the most recently started ones.
+    fn ranked(&self) -> Vec<usize> {
+        let stopped = self.jobs.iter().filter(|j| j.state == JobState::Stopped);
+        let others = self.jobs.iter().filter(|j| j.state != JobState::Stopped);
+        stopped.rev().chain(others.rev()).map(|j| j.id).collect()
+    }
+
+    pub fn current(&self) -> Option<usize> {
+        self.ranked().first().copied()
+    }
+
+    /// Resolve a job spec: `%n`, `%%`, `%+`, `%-`, `%prefix` or `%?text`.
+    pub fn resolve(&self, spec: &str) -> Result<usize, String> {
+        let ranked = self.ranked();
+        let body = spec.strip_prefix('%').unwrap_or(spec);
+        let found = match body {
+            "" | "%" | "+" => ranked.first().copied(),
+            "-" => ranked.get(1).or(ranked.first()).copied(),
+            _ => {
+                if let Ok(n) = body.parse::<usize>() {
+                    self.get(n).map(|j| j.id)
+                } else {
+                    let matching: Vec<usize> = match body.strip_prefix('?') {
+                        Some(text) => self.jobs.iter().filter(|j| j.command.contains(text)).map(|j| j.id).collect(),
+                        None => self.jobs.iter().filter(|j| j.command.starts_with(body)).map(|j| j.id).collect(),
+                    };
+                    if matching.len() > 1 {
+                        return Err(format!("{}: ambiguous job spec", spec));
+                    }
+                    matching.first().copied()
+                }
+            }
+        };
+        found.ok_or_else(|| format!("{}: no such job", spec))
+    }
+
+    /// The `jobs` line for a job: `[1]+  Running    sleep 10 &`, with the
+    /// process group id after the mark if `long`.
+    pub fn describe(&self, id: usize, long: bool) -> String {
+        let Some(job) = self.get(id) else { return String::new() };
+        let ranked = self.ranked();
+        let mark = if ranked.first() == Some(&id) {
+            '+'
+        } else if ranked.get(1) == Some(&id) {
+            '-'
+        } else {
+            ' '
+        };
+        let state = match job.state {
+            JobState::Running => "Running".to_string(),
+            JobState::Stopped => "Stopped".to_string(),
+            JobState::Done(_) if let Some(sig) = job.signal => sys::signal_description(sig),
+            JobState::Done(0) => "Done".to_string(),
+            JobState::Done(code) => format!("Exit {}", code),
+        };
+        if long {
+            format!("[{}]{} {} {:<24}{}", job.id, mark, job.pgid, state, job.command)
+        } else {
+            format!("[{}]{}  {:<24}{}", job.id, mark, state, job.command)
+        }
+    }
+
+    /// Collect state changes of all jobs without blocking.
+    pub fn poll(&mut self) {
+        for job in &mut self.jobs {
+            for pid in job.pids.clone() {
+                let options = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+                match sys::wait(pid, options) {
+                    Ok(Some(status)) => job.update(pid, status),
+                    Ok(None) => {}
+                    // Reaped elsewhere, or not our child any more.
+                    Err(_) => job.update(pid, WaitStatus::Exited(Default::default())),
+                }
+            }
+        }
+    }
+
+    /// Print a line for each job that finished or stopped since the last
+    /// report, and drop finished jobs from the table.
+    pub fn report_changes(&mut self) {
+        self.poll();
+        for id in self.ids() {
+            if self.get(id).is_some_and(|j| !j.notified && j.state != JobState::Running) {
+                eprintln!("{}", self.describe(id, false));
+            }
+        }
+        self.forget_reported();
+    }
+
+    /// Mark every job as reported and drop the finished ones.
+    pub fn forget_reported(&mut self) {
+        for job in &mut self.jobs {
+            job.notified = true;
+        }
+        self.jobs.retain(|j| !matches!(j.state, JobState::Done(_)));
+    }
+
+    /// Block until the job finishes, and remove it. Returns its status.
+    pub fn wait_for(&mut self, id: usize) -> i32 {
+        let Some(mut job) = self.remove(id) else { return 127 };
+        for pid in job.pids.clone() {
+            match sys::wait_pid(pid) {
+                Ok(status) => job.update(pid, WaitStatus::Exited(status)),
+                Err(_) => job.update(pid, WaitStatus::Exited(Default::default())),
+            }
+        }
+        job.code
+    }
+}
+
+/// Run a job in the foreground: give it the terminal, wait until every
+/// process has exited or the job stops, then take the terminal back.
+/// A stopped job is put in the job table. Returns the job's status.
+pub fn wait_foreground(shell: &mut Shell, job: Job) -> i32 {
+    wait_pipeline(shell, job).0
+}
+
+/// Like `wait_foreground`, also giving the status of each process that
+/// exited.
+pub fn wait_pipeline(shell: &mut Shell, mut job: Job) -> (i32, Vec<(Pid, i32)>) {
+    if let Some(term) = &shell.terminal {
+        sys::tcsetpgrp(term.fd, job.pgid).ok();
+    }
+
+    for pid in job.pids.clone() {
+        match sys::wait(pid, libc::WUNTRACED) {
+            Ok(Some(status)) => job.update(pid, status),
+            Ok(None) => {}
+            Err(_) => job.update(pid, WaitStatus::Exited(Default::default())),
+        }
+    }
+
+    if let Some(term) = &shell.terminal {
+        sys::tcsetpgrp(term.fd, term.shell_pgid).ok();
+        if job.state == JobState::Stopped {
+            job.modes = sys::tcgetattr(term.fd).ok();
+        }
+        sys::tcsetattr(term.fd, &term.modes).ok();
+    }
+
+    let exits = job.exits.clone();
+    let code = match job.state {
+        JobState::Stopped => {
+            let id = shell.jobs.insert(job);
+            eprintln!();
+            eprintln!("{}", shell.jobs.describe(id, false));
+            if let Some(job) = shell.jobs.jobs.iter_mut().find(|j| j.id == id) {
+                job.notified = true;
+            }
+            128 + libc::SIGTSTP
+        }
+        _ => {
+            // Ctrl-C in a foreground job also stops the list it belongs to.
+            // A script carries on; had the Ctrl-C reached it, it would have
+            // been killed too.
+            if job.signal == Some(libc::SIGINT) && shell.interactive {
+                sys::set_interrupted(true);
+            }
+            // Other deaths by signal are worth mentioning, as in "Killed".
+            if let Some(sig) = job.signal.filter(|&sig| shell.interactive && sig != libc::SIGINT && sig != libc::SIGPIPE) {
+                eprintln!("{}", sys::signal_description(sig));
+            }
+            job.code
+        }
+    };
+    (code, exits)
+}
+
+/// Continue a stopped or background job in the foreground (`fg`).
+/// The command is echoed to `out`.
+pub fn foreground(shell: &mut Shell, id: usize, out: &mut dyn Write) -> i32 {
+    let Some(mut job) = shell.jobs.remove(id) else { return 1 };
+    writeln!(out, "{}", job.command.trim_end_matches(" &")).ok();
+    out.flush().ok();
+    if let (Some(term), Some(modes)) = (&shell.terminal, job.modes.take()) {
+        sys::tcsetpgrp(term.fd, job.pgid).ok();
+        sys::tcsetattr(term.fd, &modes).ok();
+    }
+    sys::kill(-job.pgid, libc::SIGCONT).ok();
+    job.state = JobState::Running;
+    job.notified = false;
+    wait_foreground(shell, job)
+}
+
+/// Continue a stopped job in the background (`bg`), noting it on `out`.
+pub fn background(shell: &mut Shell, id: usize, out: &mut dyn Write) -> i32 {
+    let Some(job) = shell.jobs.jobs.iter_mut().find(|j| j.id == id) else { return 1 };
+    if sys::kill(-job.pgid, libc::SIGCONT).is_err() {
+        return 1;
+    }
+    job.state = JobState::Running;
+    if !job.command.ends_with(" &") {
+        job.command.push_str(" &");
+    }
+    writeln!(out, "[{}] {}", job.id, job.command).ok();
+    0
+}
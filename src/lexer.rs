@@ -1,144 +1,992 @@
-use std::env;
+use std::collections::HashMap;
+use std::fmt;
 
+use crate::ast::List;
+use crate::parser::{self, ParseError};
+
+/// One piece of a word, remembering how it was quoted so that expansion can
+/// happen later, at execution time.
 #[derive(Debug, Clone, PartialEq)]
-pub enum Token {
-    Word(String),
-    Pipe,
-    Semicolon,
-    And,                   // &&
-    Or,                    // ||
-    RedirectOut(String),   // > file
-    RedirectAppend(String), // >> file
-    RedirectIn(String),    // < file
-}
-
-pub fn tokenize(input: &str, last_exit_code: i32) -> Vec<Token> {
-    let mut tokens = Vec::new();
-    let mut current = String::new();
-    let mut chars = input.chars().peekable();
-    let mut in_single = false;
-    let mut in_double = false;
-
-    let flush = |current: &mut String, tokens: &mut Vec<Token>| {
-        if !current.is_empty() {
-            tokens.push(Token::Word(std::mem::take(current)));
-        }
-    };
-
-    while let Some(c) = chars.next() {
-        match c {
-            '\'' if !in_double => {
-                in_single = !in_single;
+pub enum WordPart {
+    /// Unquoted literal text.
+    Literal(String),
+    /// Text inside single quotes, or a backslash-escaped character.
+    Quoted(String),
+    /// The contents of a double-quoted string.
+    DoubleQuoted(Vec<WordPart>),
+    /// `$name`, `${name}`, or a special or positional parameter such as
+    /// `$?` or `$1`.
+    Param(String),
+    /// `${...}` with an operator, such as `${name:-word}` or `${#name}`.
+    ParamExp(Box<ParamExp>),
+    /// `$((expression))`.
+    Arith(Word),
+    /// `$(list)` or `` `list` ``.
+    CommandSub(List),
+    /// A leading `~`, with the optional user name that follows it.
+    Tilde(String),
+}
+
+/// A parameter expansion with an operator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamExp {
+    pub name: String,
+    /// `${!name...}`: the parameter is the one named by `name`'s value.
+    pub indirect: bool,
+    /// `${name[index]}`: an element of an array, or with `@` or `*` all
+    /// of them.
+    pub index: Option<Word>,
+    pub op: ParamOp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamOp {
+    /// Just the value, as in `${!name}`.
+    Value,
+    /// `${#name}`: the length of the value.
+    Length,
+    /// `${name-word}`, `${name=word}`, `${name?word}` or `${name+word}`;
+    /// with `colon`, an empty value counts as unset.
+    Default { op: char, colon: bool, word: Word },
+    /// `${name#pattern}` and `${name%pattern}`, or with `##` and `%%` the
+    /// longest match.
+    Remove { suffix: bool, longest: bool, pattern: Word },
+    /// `${name/pattern/with}`, or with `//` every match.
+    Replace { all: bool, pattern: Word, with: Word },
+    /// `${name:offset}` or `${name:offset:length}`, both arithmetic.
+    Substring { offset: Word, length: Option<Word> },
+    /// `${name^pattern}` and `${name,pattern}` change the case of the first
+    /// character if it matches, `^^` and `,,` of every matching one.
+    Case { upper: bool, all: bool, pattern: Word },
+}
+
+impl fmt::Display for ParamExp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("${")?;
+        if self.indirect {
+            f.write_str("!")?;
+        }
+        if self.op == ParamOp::Length {
+            f.write_str("#")?;
+        }
+        f.write_str(&self.name)?;
+        if let Some(index) = &self.index {
+            write!(f, "[{}]", index)?;
+        }
+        match &self.op {
+            ParamOp::Value | ParamOp::Length => {}
+            ParamOp::Default { op, colon, word } => write!(f, "{}{}{}", if *colon { ":" } else { "" }, op, word)?,
+            ParamOp::Remove { suffix, longest, pattern } => {
+                let op = if *suffix { "%" } else { "#" };
+                write!(f, "{}{}{}", op, if *longest { op } else { "" }, pattern)?
             }
-            '"' if !in_single => {
-                in_double = !in_double;
+            ParamOp::Replace { all, pattern, with } => {
+                write!(f, "/{}{}/{}", if *all { "/" } else { "" }, pattern, with)?
             }
-            '\\' if !in_single => {
-                if let Some(nc) = chars.next() {
-                    current.push(nc);
+            ParamOp::Substring { offset, length } => {
+                write!(f, ":{}", offset)?;
+                if let Some(length) = length {
+                    write!(f, ":{}", length)?;
                 }
             }
-            '$' if !in_single => {
-                let mut name = String::new();
-                if chars.peek() == Some(&'{') {
-                    chars.next();
-                    while let Some(&ch) = chars.peek() {
-                        if ch == '}' { chars.next(); break; }
-                        name.push(ch);
-                        chars.next();
+            ParamOp::Case { upper, all, pattern } => {
+                let op = if *upper { "^" } else { "," };
+                write!(f, "{}{}{}", op, if *all { op } else { "" }, pattern)?
+            }
+        }
+        f.write_str("}")
+    }
+}
+
+/// A shell word as written in the source, before any expansion.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Word {
+    pub parts: Vec<WordPart>,
+}
+
+impl Word {
+    /// True if expanding the word runs a command substitution.
+    pub fn has_command_sub(&self) -> bool {
+        fn any(parts: &[WordPart]) -> bool {
+            parts.iter().any(|p| match p {
+                WordPart::CommandSub(_) => true,
+                WordPart::DoubleQuoted(inner) => any(inner),
+                _ => false,
+            })
+        }
+        any(&self.parts)
+    }
+
+    /// The word's text if it is a single unquoted literal, e.g. a reserved word.
+    pub fn as_literal(&self) -> Option<&str> {
+        match self.parts.as_slice() {
+            [WordPart::Literal(s)] => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_parts(f, &self.parts, false)
+    }
+}
+
+/// Write word parts back out as shell source.
+fn write_parts(f: &mut fmt::Formatter<'_>, parts: &[WordPart], in_double: bool) -> fmt::Result {
+    for (i, part) in parts.iter().enumerate() {
+        match part {
+            WordPart::Literal(s) if in_double => {
+                for c in s.chars() {
+                    if matches!(c, '"' | '\\' | '$' | '`') {
+                        f.write_str("\\")?;
                     }
-                } else if chars.peek() == Some(&'?') {
-                    chars.next();
-                    current.push_str(&last_exit_code.to_string());
-                    continue;
+                    write!(f, "{}", c)?;
+                }
+            }
+            WordPart::Literal(s) => f.write_str(s)?,
+            WordPart::Quoted(s) => write!(f, "'{}'", s.replace('\'', "'\\''"))?,
+            WordPart::DoubleQuoted(inner) => {
+                f.write_str("\"")?;
+                write_parts(f, inner, true)?;
+                f.write_str("\"")?;
+            }
+            WordPart::Param(name) => {
+                // Braces are needed if the next part would extend the name.
+                let joins = match parts.get(i + 1) {
+                    Some(WordPart::Literal(next)) => next.starts_with(|c: char| c.is_alphanumeric() || c == '_'),
+                    _ => false,
+                };
+                let simple = name.len() == 1
+                    || (name.chars().all(|c| c.is_alphanumeric() || c == '_') && !name.starts_with(|c: char| c.is_ascii_digit()));
+                if simple && !joins {
+                    write!(f, "${}", name)?;
                 } else {
-                    while let Some(&ch) = chars.peek() {
-                        if ch.is_alphanumeric() || ch == '_' {
-                            name.push(ch);
-                            chars.next();
+                    write!(f, "${{{}}}", name)?;
+                }
+            }
+            WordPart::ParamExp(exp) => write!(f, "{}", exp)?,
+            WordPart::Arith(expr) => write!(f, "$(({}))", expr)?,
+            WordPart::CommandSub(list) => write!(f, "$({})", list)?,
+            WordPart::Tilde(user) => write!(f, "~{}", user)?,
+        }
+    }
+    Ok(())
+}
+
+/// Byte offsets of a token in the source text.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Word(Word),
+    Pipe,      // |
+    And,       // &&
+    Or,        // ||
+    Semicolon, // ;
+    DSemi,     // ;;
+    SemiAnd,   // ;&
+    DSemiAnd,  // ;;&
+    Amp,       // &
+    Newline,
+    LParen,    // (
+    RParen,    // )
+    /// The expression between `((` and `))`.
+    Arith(Word),
+    Less,      // <
+    Great,     // >
+    DGreat,    // >>
+    LessGreat, // <>
+    LessAnd,   // <&
+    GreatAnd,  // >&
+    Clobber,   // >|
+    AndGreat,  // &>
+    AndDGreat, // &>>
+    /// `<<` or `<<-`, with the here-document body once the lexer has read
+    /// the lines after the command.
+    DLess(Option<Word>),
+    TLess,     // <<<
+    /// The digits of `2>`, written right before a redirection operator.
+    IoNumber(i32),
+}
+
+/// Split the input into tokens, each paired with its span.
+pub fn tokenize(input: &str) -> Result<Vec<(Token, Span)>, ParseError> {
+    tokenize_with_aliases(input, &HashMap::new())
+}
+
+/// Split the input into tokens, expanding aliases in the command
+/// substitutions it contains.
+pub fn tokenize_with_aliases(input: &str, aliases: &HashMap<String, String>) -> Result<Vec<(Token, Span)>, ParseError> {
+    Lexer { input, chars: input.char_indices().peekable(), aliases }.tokens(false)
+}
+
+struct Lexer<'a> {
+    input: &'a str,
+    chars: std::iter::Peekable<std::str::CharIndices<'a>>,
+    aliases: &'a HashMap<String, String>,
+}
+
+impl Lexer<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, c)| c)
+    }
+
+    fn next(&mut self) -> Option<char> {
+        self.chars.next().map(|(_, c)| c)
+    }
+
+    /// An error for a construct starting at `start` that runs off the end
+    /// of the input.
+    fn error(&self, start: usize, message: &str) -> ParseError {
+        let span = Span { start, end: self.input.len() };
+        ParseError::new(self.input, span, message.to_string(), None).incomplete()
+    }
+
+    /// Lex tokens up to the end of input or, when `nested`, up to the `)`
+    /// that closes a command substitution.
+    fn tokens(&mut self, nested: bool) -> Result<Vec<(Token, Span)>, ParseError> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+        let mut word = Word::default();
+        let mut lit = String::new();
+        let mut word_start = None;
+        // `<<` tokens whose bodies start after the current line, and
+        // whether they strip leading tabs.
+        let mut here_docs: Vec<(usize, bool)> = Vec::new();
+        let mut cases = CaseTracker::default();
+
+        let flush = |word: &mut Word, lit: &mut String, word_start: &mut Option<usize>, end: usize, tokens: &mut Vec<(Token, Span)>| {
+            push_literal(word, lit);
+            if let Some(start) = word_start.take() {
+                tokens.push((Token::Word(std::mem::take(word)), Span { start, end }));
+            }
+        };
+
+        while let Some((start, c)) = self.chars.next() {
+            let op = |tok: Token, len: usize| (tok, Span { start, end: start + len });
+            match c {
+                ' ' | '\t' => flush(&mut word, &mut lit, &mut word_start, start, &mut tokens),
+                '\n' => {
+                    flush(&mut word, &mut lit, &mut word_start, start, &mut tokens);
+                    tokens.push(op(Token::Newline, 1));
+                    for (at, strip) in here_docs.drain(..) {
+                        self.here_doc(&mut tokens, at, strip)?;
+                    }
+                }
+                '#' if word_start.is_none() => {
+                    while let Some(ch) = self.peek() {
+                        if ch == '\n' { break; }
+                        self.next();
+                    }
+                }
+                '|' => {
+                    flush(&mut word, &mut lit, &mut word_start, start, &mut tokens);
+                    if self.peek() == Some('|') {
+                        self.next();
+                        tokens.push(op(Token::Or, 2));
+                    } else {
+                        tokens.push(op(Token::Pipe, 1));
+                    }
+                }
+                '&' => {
+                    flush(&mut word, &mut lit, &mut word_start, start, &mut tokens);
+                    if self.peek() == Some('&') {
+                        self.next();
+                        tokens.push(op(Token::And, 2));
+                    } else if self.peek() == Some('>') {
+                        self.next();
+                        if self.peek() == Some('>') {
+                            self.next();
+                            tokens.push(op(Token::AndDGreat, 3));
                         } else {
-                            break;
+                            tokens.push(op(Token::AndGreat, 2));
+                        }
+                    } else {
+                        tokens.push(op(Token::Amp, 1));
+                    }
+                }
+                ';' => {
+                    flush(&mut word, &mut lit, &mut word_start, start, &mut tokens);
+                    let (tok, len) = match self.peek() {
+                        Some(';') => {
+                            self.next();
+                            if self.peek() == Some('&') {
+                                self.next();
+                                (Token::DSemiAnd, 3)
+                            } else {
+                                (Token::DSemi, 2)
+                            }
+                        }
+                        Some('&') => {
+                            self.next();
+                            (Token::SemiAnd, 2)
+                        }
+                        _ => (Token::Semicolon, 1),
+                    };
+                    tokens.push(op(tok, len));
+                }
+                '(' => {
+                    flush(&mut word, &mut lit, &mut word_start, start, &mut tokens);
+                    if self.peek() == Some('(')
+                        && let Some(end) = arith_end(&self.input[start + 2..])
+                    {
+                        let end = start + 2 + end;
+                        self.next();
+                        let expr = self.arith_expr(end)?;
+                        tokens.push((Token::Arith(expr), Span { start, end: end + 2 }));
+                        continue;
+                    }
+                    // The parentheses around a `case` pattern are not
+                    // counted, so they cannot end a command substitution.
+                    if !cases.in_pattern(&tokens) {
+                        depth += 1;
+                    }
+                    tokens.push(op(Token::LParen, 1));
+                }
+                ')' => {
+                    flush(&mut word, &mut lit, &mut word_start, start, &mut tokens);
+                    if cases.in_pattern(&tokens) {
+                        tokens.push(op(Token::RParen, 1));
+                        continue;
+                    }
+                    if nested && depth == 0 {
+                        return Ok(tokens);
+                    }
+                    tokens.push(op(Token::RParen, 1));
+                    depth -= 1;
+                }
+                '<' | '>' => {
+                    // Digits right before the operator name the descriptor.
+                    let io_number = match word_start {
+                        Some(at) if word.parts.is_empty() && lit.bytes().all(|b| b.is_ascii_digit()) => {
+                            lit.parse().ok().map(|n| (n, at))
+                        }
+                        _ => None,
+                    };
+                    match io_number {
+                        Some((n, at)) => {
+                            lit.clear();
+                            word_start = None;
+                            tokens.push((Token::IoNumber(n), Span { start: at, end: start }));
+                        }
+                        None => flush(&mut word, &mut lit, &mut word_start, start, &mut tokens),
+                    }
+                    if c == '<' && self.peek() == Some('<') {
+                        self.next();
+                        match self.peek() {
+                            Some('<') => {
+                                self.next();
+                                tokens.push(op(Token::TLess, 3));
+                            }
+                            Some('-') => {
+                                self.next();
+                                here_docs.push((tokens.len(), true));
+                                tokens.push(op(Token::DLess(None), 3));
+                            }
+                            _ => {
+                                here_docs.push((tokens.len(), false));
+                                tokens.push(op(Token::DLess(None), 2));
+                            }
+                        }
+                        continue;
+                    }
+                    let (tok, len) = match (c, self.peek()) {
+                        ('<', Some('>')) => (Token::LessGreat, 2),
+                        ('<', Some('&')) => (Token::LessAnd, 2),
+                        ('<', _) => (Token::Less, 1),
+                        ('>', Some('>')) => (Token::DGreat, 2),
+                        ('>', Some('&')) => (Token::GreatAnd, 2),
+                        ('>', Some('|')) => (Token::Clobber, 2),
+                        _ => (Token::Great, 1),
+                    };
+                    if len == 2 {
+                        self.next();
+                    }
+                    tokens.push(op(tok, len));
+                }
+                '\'' => {
+                    word_start.get_or_insert(start);
+                    push_literal(&mut word, &mut lit);
+                    let mut quoted = String::new();
+                    loop {
+                        match self.next() {
+                            Some('\'') => break,
+                            Some(ch) => quoted.push(ch),
+                            None => return Err(self.error(start, "unterminated single quote")),
                         }
                     }
+                    word.parts.push(WordPart::Quoted(quoted));
+                }
+                '"' => {
+                    word_start.get_or_insert(start);
+                    push_literal(&mut word, &mut lit);
+                    let parts = self.double_quoted(start)?;
+                    word.parts.push(WordPart::DoubleQuoted(parts));
+                }
+                '`' => {
+                    word_start.get_or_insert(start);
+                    push_literal(&mut word, &mut lit);
+                    word.parts.push(self.backquoted(start)?);
+                }
+                '\\' => match self.next() {
+                    Some('\n') => {}
+                    Some(nc) => {
+                        word_start.get_or_insert(start);
+                        push_literal(&mut word, &mut lit);
+                        word.parts.push(WordPart::Quoted(nc.to_string()));
+                    }
+                    None => return Err(self.error(start, "unexpected end of input after `\\`")),
+                },
+                '$' => {
+                    word_start.get_or_insert(start);
+                    match self.dollar(start)? {
+                        Some(part) => {
+                            push_literal(&mut word, &mut lit);
+                            word.parts.push(part);
+                        }
+                        None => lit.push('$'),
+                    }
+                }
+                '~' if word_start.is_none() => {
+                    word_start = Some(start);
+                    let mut user = String::new();
+                    while let Some(ch) = self.peek() {
+                        if ch == '/' || is_meta(ch) { break; }
+                        user.push(ch);
+                        self.next();
+                    }
+                    word.parts.push(WordPart::Tilde(user));
+                }
+                _ => {
+                    word_start.get_or_insert(start);
+                    lit.push(c);
                 }
-                current.push_str(&env::var(&name).unwrap_or_default());
             }
-            '~' if !in_single && !in_double && current.is_empty() => {
-                let home = env::var("HOME")
-                    .or_else(|_| env::var("USERPROFILE"))
-                    .unwrap_or_else(|_| "~".to_string());
-                current.push_str(&home);
+        }
+
+        if nested {
+            return Err(self.error(self.input.len(), "unterminated command substitution"));
+        }
+        let end = self.input.len();
+        flush(&mut word, &mut lit, &mut word_start, end, &mut tokens);
+        // A here-document with a delimiter needs at least one more line.
+        if let Some(&(at, _)) = here_docs.iter().find(|&&(at, _)| matches!(tokens.get(at + 1), Some((Token::Word(_), _)))) {
+            return Err(self.error(tokens[at].1.start, "unterminated here-document"));
+        }
+        Ok(tokens)
+    }
+
+    /// Read the body of the here-document whose `<<` is `tokens[at]`, from
+    /// the lines that follow, up to a line holding just the delimiter.
+    /// A quoted delimiter makes the body literal.
+    fn here_doc(&mut self, tokens: &mut [(Token, Span)], at: usize, strip: bool) -> Result<(), ParseError> {
+        let Some((Token::Word(delimiter), _)) = tokens.get(at + 1) else { return Ok(()) };
+        let quoted = delimiter.parts.iter().any(|p| !matches!(p, WordPart::Literal(_)));
+        let delimiter = unquoted(&delimiter.parts);
+        let start = tokens[at].1.start;
+
+        let mut parts = Vec::new();
+        let mut lit = String::new();
+        loop {
+            let Some(&(line_start, _)) = self.chars.peek() else {
+                return Err(self.error(start, "unterminated here-document"));
+            };
+            let line_end = self.input[line_start..].find('\n').map_or(self.input.len(), |i| line_start + i);
+            let line = &self.input[line_start..line_end];
+            let text = if strip { line.trim_start_matches('\t') } else { line };
+            if text == delimiter || quoted {
+                while self.chars.peek().is_some_and(|&(i, _)| i <= line_end) {
+                    self.chars.next();
+                }
+                if text == delimiter {
+                    break;
+                }
+                lit.push_str(text);
+                lit.push('\n');
+                continue;
             }
-            '|' if !in_single && !in_double => {
-                flush(&mut current, &mut tokens);
-                if chars.peek() == Some(&'|') {
-                    chars.next();
-                    tokens.push(Token::Or);
-                } else {
-                    tokens.push(Token::Pipe);
+
+            while strip && self.peek() == Some('\t') {
+                self.next();
+            }
+            while let Some((at, c)) = self.chars.next() {
+                match c {
+                    '\n' => {
+                        lit.push('\n');
+                        break;
+                    }
+                    '\\' => match self.peek() {
+                        Some('\n') => { self.next(); }
+                        Some(nc @ ('$' | '`' | '\\')) => {
+                            self.next();
+                            lit.push(nc);
+                        }
+                        _ => lit.push('\\'),
+                    },
+                    '$' => match self.dollar(at)? {
+                        Some(part) => {
+                            if !lit.is_empty() {
+                                parts.push(WordPart::Literal(std::mem::take(&mut lit)));
+                            }
+                            parts.push(part);
+                        }
+                        None => lit.push('$'),
+                    },
+                    '`' => {
+                        if !lit.is_empty() {
+                            parts.push(WordPart::Literal(std::mem::take(&mut lit)));
+                        }
+                        parts.push(self.backquoted(at)?);
+                    }
+                    _ => lit.push(c),
                 }
             }
-            '&' if !in_single && !in_double => {
-                if chars.peek() == Some(&'&') {
-                    chars.next();
-                    flush(&mut current, &mut tokens);
-                    tokens.push(Token::And);
-                } else {
-                    current.push(c);
-                }
-            }
-            ';' if !in_single && !in_double => {
-                flush(&mut current, &mut tokens);
-                tokens.push(Token::Semicolon);
-            }
-            '>' if !in_single && !in_double => {
-                flush(&mut current, &mut tokens);
-                let append = chars.peek() == Some(&'>');
-                if append { chars.next(); }
-                // Collect the filename (skip spaces)
-                while chars.peek() == Some(&' ') { chars.next(); }
-                let mut file = String::new();
-                while let Some(&ch) = chars.peek() {
-                    if ch == ' ' || ch == '|' || ch == ';' || ch == '<' || ch == '>' { break; }
-                    file.push(ch);
-                    chars.next();
-                }
-                let file = expand_tilde(&file);
-                if append {
-                    tokens.push(Token::RedirectAppend(file));
-                } else {
-                    tokens.push(Token::RedirectOut(file));
+        }
+
+        if quoted {
+            parts.push(WordPart::Quoted(lit));
+        } else if !lit.is_empty() {
+            parts.push(WordPart::Literal(lit));
+        }
+        tokens[at].0 = Token::DLess(Some(Word { parts }));
+        Ok(())
+    }
+
+    /// Read the body of a double-quoted string; the opening quote at `start`
+    /// is already consumed.
+    fn double_quoted(&mut self, start: usize) -> Result<Vec<WordPart>, ParseError> {
+        let mut parts = Vec::new();
+        let mut lit = String::new();
+        loop {
+            let Some((at, c)) = self.chars.next() else {
+                return Err(self.error(start, "unterminated double quote"));
+            };
+            match c {
+                '"' => break,
+                '\\' => match self.peek() {
+                    Some('\n') => { self.next(); }
+                    Some(nc @ ('$' | '`' | '"' | '\\')) => {
+                        self.next();
+                        lit.push(nc);
+                    }
+                    _ => lit.push('\\'),
+                },
+                '$' => match self.dollar(at)? {
+                    Some(part) => {
+                        if !lit.is_empty() {
+                            parts.push(WordPart::Literal(std::mem::take(&mut lit)));
+                        }
+                        parts.push(part);
+                    }
+                    None => lit.push('$'),
+                },
+                '`' => {
+                    if !lit.is_empty() {
+                        parts.push(WordPart::Literal(std::mem::take(&mut lit)));
+                    }
+                    parts.push(self.backquoted(at)?);
+                }
+                _ => lit.push(c),
+            }
+        }
+        if !lit.is_empty() {
+            parts.push(WordPart::Literal(lit));
+        }
+        Ok(parts)
+    }
+
+    /// Read a backquoted command substitution; the opening backquote at
+    /// `start` is already consumed.
+    fn backquoted(&mut self, start: usize) -> Result<WordPart, ParseError> {
+        let mut inner = String::new();
+        loop {
+            match self.next() {
+                Some('`') => break,
+                Some('\\') => match self.next() {
+                    Some(nc @ ('$' | '`' | '\\')) => inner.push(nc),
+                    Some(nc) => {
+                        inner.push('\\');
+                        inner.push(nc);
+                    }
+                    None => return Err(self.error(start, "unterminated backquote")),
+                },
+                Some(ch) => inner.push(ch),
+                None => return Err(self.error(start, "unterminated backquote")),
+            }
+        }
+        // The body is its own source text, so report errors at the backquote.
+        parser::parse_with_aliases(&inner, self.aliases).map(WordPart::CommandSub).map_err(|e| {
+            let span = Span { start, end: start + 1 };
+            ParseError::new(self.input, span, format!("in backquotes: {}", e.message), e.expected)
+        })
+    }
+
+    /// Parse what follows a `$` at `start`. Returns `None` when the `$` is
+    /// just a literal.
+    fn dollar(&mut self, start: usize) -> Result<Option<WordPart>, ParseError> {
+        match self.peek() {
+            Some('(') => {
+                self.next();
+                let rest = &self.input[start + 2..];
+                if rest.starts_with('(')
+                    && let Some(end) = arith_end(&rest[1..])
+                {
+                    self.next();
+                    return self.arith_expr(start + 3 + end).map(|expr| Some(WordPart::Arith(expr)));
+                }
+                let tokens = self.tokens(true)?;
+                parser::parse_tokens(self.input, tokens, self.aliases).map(|list| Some(WordPart::CommandSub(list)))
+            }
+            Some('{') => {
+                self.next();
+                self.braced(start).map(Some)
+            }
+            // Special parameters and positional parameters take one character.
+            Some(c @ ('?' | '!' | '#' | '@' | '*' | '$' | '-' | '0'..='9')) => {
+                self.next();
+                Ok(Some(WordPart::Param(c.to_string())))
+            }
+            Some(ch) if ch.is_alphanumeric() || ch == '_' => {
+                let mut name = String::new();
+                while let Some(ch) = self.peek() {
+                    if ch.is_alphanumeric() || ch == '_' {
+                        name.push(ch);
+                        self.next();
+                    } else {
+                        break;
+                    }
+                }
+                Ok(Some(WordPart::Param(name)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Read an arithmetic expression up to `end`, where its closing `))`
+    /// starts; the opening `((` is already consumed. Parameters and command
+    /// substitutions in it are expanded before it is evaluated.
+    fn arith_expr(&mut self, end: usize) -> Result<Word, ParseError> {
+        let mut word = Word::default();
+        let mut lit = String::new();
+        while let Some(&(at, c)) = self.chars.peek().filter(|&&(i, _)| i < end) {
+            self.next();
+            match c {
+                '$' => match self.dollar(at)? {
+                    Some(part) => {
+                        push_literal(&mut word, &mut lit);
+                        word.parts.push(part);
+                    }
+                    None => lit.push('$'),
+                },
+                '`' => {
+                    push_literal(&mut word, &mut lit);
+                    word.parts.push(self.backquoted(at)?);
+                }
+                _ => lit.push(c),
+            }
+        }
+        push_literal(&mut word, &mut lit);
+        self.next();
+        self.next();
+        Ok(word)
+    }
+
+    /// Parse a `${...}` expansion; `${` at `start` is already consumed.
+    fn braced(&mut self, start: usize) -> Result<WordPart, ParseError> {
+        let rest = &self.input[self.chars.peek().map_or(self.input.len(), |&(i, _)| i)..];
+        // `${#}` and `${!}` are parameters; followed by a parameter name
+        // they are operators.
+        let operator = |prefix| {
+            let mut chars = rest.chars();
+            chars.next() == Some(prefix)
+                && chars.next().is_some_and(|c| c.is_alphanumeric() || matches!(c, '_' | '?' | '@' | '*' | '$' | '!'))
+        };
+        let length = operator('#');
+        let indirect = !length && operator('!');
+        if length || indirect {
+            self.next();
+        }
+
+        let mut name = String::new();
+        match self.peek() {
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                while let Some(c) = self.peek().filter(|&c| c.is_alphanumeric() || c == '_') {
+                    name.push(c);
+                    self.next();
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                while let Some(c) = self.peek().filter(char::is_ascii_digit) {
+                    name.push(c);
+                    self.next();
+                }
+            }
+            Some(c @ ('?' | '!' | '#' | '@' | '*' | '$' | '-')) => {
+                name.push(c);
+                self.next();
+            }
+            Some(_) => return Err(self.bad_substitution(start)),
+            None => return Err(self.error(start, "missing `}` in parameter expansion")),
+        }
+
+        let index = if self.peek() == Some('[') && parser::is_name(&name) {
+            self.next();
+            let index = self.op_word(start, &[']'])?;
+            if self.next() != Some(']') {
+                return Err(self.bad_substitution(start));
+            }
+            Some(index)
+        } else {
+            None
+        };
+
+        let op = match self.next() {
+            Some('}') if length => return Ok(self.param_exp(name, indirect, index, ParamOp::Length)),
+            Some('}') if indirect || index.is_some() => return Ok(self.param_exp(name, indirect, index, ParamOp::Value)),
+            Some('}') => return Ok(WordPart::Param(name)),
+            _ if length => return Err(self.bad_substitution(start)),
+            Some(':') => match self.peek() {
+                Some(op @ ('-' | '=' | '?' | '+')) => {
+                    self.next();
+                    ParamOp::Default { op, colon: true, word: self.op_word(start, &[])? }
+                }
+                _ => {
+                    let offset = self.op_word(start, &[':'])?;
+                    let length = match self.peek() {
+                        Some(':') => {
+                            self.next();
+                            Some(self.op_word(start, &[])?)
+                        }
+                        _ => None,
+                    };
+                    ParamOp::Substring { offset, length }
+                }
+            },
+            Some(op @ ('-' | '=' | '?' | '+')) => ParamOp::Default { op, colon: false, word: self.op_word(start, &[])? },
+            Some(op @ ('#' | '%')) => {
+                let longest = self.peek() == Some(op);
+                if longest {
+                    self.next();
+                }
+                ParamOp::Remove { suffix: op == '%', longest, pattern: self.op_word(start, &[])? }
+            }
+            Some('/') => {
+                let all = self.peek() == Some('/');
+                if all {
+                    self.next();
+                }
+                let pattern = self.op_word(start, &['/'])?;
+                let with = match self.peek() {
+                    Some('/') => {
+                        self.next();
+                        self.op_word(start, &[])?
+                    }
+                    _ => Word::default(),
+                };
+                ParamOp::Replace { all, pattern, with }
+            }
+            Some(op @ ('^' | ',')) => {
+                let all = self.peek() == Some(op);
+                if all {
+                    self.next();
+                }
+                ParamOp::Case { upper: op == '^', all, pattern: self.op_word(start, &[])? }
+            }
+            Some(_) => return Err(self.bad_substitution(start)),
+            None => return Err(self.error(start, "missing `}` in parameter expansion")),
+        };
+        match self.next() {
+            Some('}') => Ok(self.param_exp(name, indirect, index, op)),
+            _ => Err(self.error(start, "missing `}` in parameter expansion")),
+        }
+    }
+
+    fn param_exp(&self, name: String, indirect: bool, index: Option<Word>, op: ParamOp) -> WordPart {
+        WordPart::ParamExp(Box::new(ParamExp { name, indirect, index, op }))
+    }
+
+    fn bad_substitution(&mut self, start: usize) -> ParseError {
+        let end = self.input[start..].find('}').map_or(self.input.len(), |i| start + i + 1);
+        while self.chars.peek().is_some_and(|&(i, _)| i < end) {
+            self.chars.next();
+        }
+        let span = Span { start, end };
+        ParseError::new(self.input, span, format!("{}: bad substitution", &self.input[start..end]), None)
+    }
+
+    /// Read the word after a `${name` operator, up to an unquoted `}` or
+    /// one of `stops`, which is left unread. Braces inside it nest.
+    fn op_word(&mut self, start: usize, stops: &[char]) -> Result<Word, ParseError> {
+        let mut word = Word::default();
+        let mut lit = String::new();
+        let mut depth = 0;
+        loop {
+            let Some(&(at, c)) = self.chars.peek() else {
+                return Err(self.error(start, "missing `}` in parameter expansion"));
+            };
+            if depth == 0 && (c == '}' || stops.contains(&c)) {
+                break;
+            }
+            self.next();
+            match c {
+                '{' => {
+                    depth += 1;
+                    lit.push(c);
+                }
+                '}' => {
+                    depth -= 1;
+                    lit.push(c);
+                }
+                '\\' => {
+                    push_literal(&mut word, &mut lit);
+                    match self.next() {
+                        Some(nc) => word.parts.push(WordPart::Quoted(nc.to_string())),
+                        None => return Err(self.error(start, "missing `}` in parameter expansion")),
+                    }
+                }
+                '\'' => {
+                    push_literal(&mut word, &mut lit);
+                    let mut quoted = String::new();
+                    loop {
+                        match self.next() {
+                            Some('\'') => break,
+                            Some(qc) => quoted.push(qc),
+                            None => return Err(self.error(at, "unterminated single quote")),
+                        }
+                    }
+                    word.parts.push(WordPart::Quoted(quoted));
+                }
+                '"' => {
+                    push_literal(&mut word, &mut lit);
+                    let inner = self.double_quoted(at)?;
+                    word.parts.push(WordPart::DoubleQuoted(inner));
+                }
+                '$' => match self.dollar(at)? {
+                    Some(part) => {
+                        push_literal(&mut word, &mut lit);
+                        word.parts.push(part);
+                    }
+                    None => lit.push('$'),
+                },
+                '`' => {
+                    push_literal(&mut word, &mut lit);
+                    word.parts.push(self.backquoted(at)?);
                 }
+                _ => lit.push(c),
             }
-            '<' if !in_single && !in_double => {
-                flush(&mut current, &mut tokens);
-                while chars.peek() == Some(&' ') { chars.next(); }
-                let mut file = String::new();
-                while let Some(&ch) = chars.peek() {
-                    if ch == ' ' || ch == '|' || ch == ';' || ch == '<' || ch == '>' { break; }
-                    file.push(ch);
-                    chars.next();
+        }
+        push_literal(&mut word, &mut lit);
+        Ok(word)
+    }
+}
+
+#[derive(PartialEq)]
+enum CasePart {
+    Subject,
+    Pattern,
+    Body,
+}
+
+/// How far the tokens lexed so far are into each `case` command, brought
+/// up to date as tokens are added, so that the parentheses around a
+/// pattern can be told apart from others.
+#[derive(Default)]
+struct CaseTracker {
+    /// The part each unfinished `case` has reached, innermost last.
+    cases: Vec<CasePart>,
+    /// A word has been seen since the last command started, so `case` and
+    /// `esac` are not reserved words.
+    in_command: bool,
+    /// How many tokens have been looked at.
+    seen: usize,
+}
+
+impl CaseTracker {
+    /// True if `tokens`, which only ever grows, ends inside the patterns
+    /// of a `case` item, where `(` and `)` only delimit the pattern.
+    fn in_pattern(&mut self, tokens: &[(Token, Span)]) -> bool {
+        for (tok, _) in &tokens[self.seen..] {
+            self.step(tok);
+        }
+        self.seen = tokens.len();
+        self.cases.last() == Some(&CasePart::Pattern)
+    }
+
+    fn step(&mut self, tok: &Token) {
+        let command_start = !self.in_command;
+        self.in_command = true;
+        match tok {
+            Token::Word(w) => {
+                let word = w.as_literal();
+                match self.cases.last_mut() {
+                    Some(part @ CasePart::Subject) if word == Some("in") => *part = CasePart::Pattern,
+                    Some(CasePart::Pattern) if word == Some("esac") => {
+                        self.cases.pop();
+                    }
+                    Some(CasePart::Body) if command_start && word == Some("esac") => {
+                        self.cases.pop();
+                    }
+                    _ if command_start && word == Some("case") => self.cases.push(CasePart::Subject),
+                    _ => {}
                 }
-                tokens.push(Token::RedirectIn(expand_tilde(&file)));
             }
-            ' ' | '\t' if !in_single && !in_double => {
-                flush(&mut current, &mut tokens);
+            Token::RParen if self.cases.last() == Some(&CasePart::Pattern) => {
+                *self.cases.last_mut().unwrap() = CasePart::Body;
+                self.in_command = false;
+            }
+            Token::DSemi | Token::SemiAnd | Token::DSemiAnd if self.cases.last() == Some(&CasePart::Body) => {
+                *self.cases.last_mut().unwrap() = CasePart::Pattern;
             }
-            _ => current.push(c),
+            Token::Newline | Token::Semicolon | Token::Pipe | Token::And | Token::Or | Token::Amp | Token::LParen => {
+                self.in_command = false;
+            }
+            _ => {}
         }
     }
+}
 
-    flush(&mut current, &mut tokens);
-    tokens
+fn push_literal(word: &mut Word, lit: &mut String) {
+    if !lit.is_empty() {
+        word.parts.push(WordPart::Literal(std::mem::take(lit)));
+    }
 }
 
-fn expand_tilde(s: &str) -> String {
-    if s == "~" || s.starts_with("~/") || s.starts_with("~\\") {
-        let home = env::var("HOME")
-            .or_else(|_| env::var("USERPROFILE"))
-            .unwrap_or_default();
-        if s.len() > 1 { format!("{}{}", home, &s[1..]) } else { home }
-    } else {
-        s.to_string()
+/// The length of the arithmetic expression at the start of `text`, which
+/// follows a `((`, if it is closed by a matching `))`. Otherwise the `((`
+/// opens two nested subshells.
+fn arith_end(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            ')' => return (chars.peek().map(|&(_, c)| c) == Some(')')).then_some(i),
+            _ => {}
+        }
     }
+    None
+}
+
+/// The text of word parts with quoting removed, as used for a
+/// here-document delimiter.
+fn unquoted(parts: &[WordPart]) -> String {
+    parts
+        .iter()
+        .map(|part| match part {
+            WordPart::Literal(s) | WordPart::Quoted(s) => s.clone(),
+            WordPart::DoubleQuoted(inner) => unquoted(inner),
+            other => Word { parts: vec![other.clone()] }.to_string(),
+        })
+        .collect()
+}
+
+/// Characters that end an unquoted word.
+fn is_meta(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '|' | '&' | ';' | '(' | ')' | '<' | '>')
 }
@@ -1,25 +1,35 @@
+mod arith;
+mod ast;
+mod brace;
 mod builtins;
 mod completer;
 mod executor;
+mod expand;
+mod glob;
+mod jobs;
 mod lexer;
 mod parser;
 mod prompt;
+mod script;
 mod shell;
+mod sys;
+mod vars;
 
 use std::env;
+use std::fs;
+use std::io::{self, Write};
 use std::path::PathBuf;
+use std::process;
 
 use rustyline::error::ReadlineError;
 use rustyline::history::DefaultHistory;
 use rustyline::{CompletionType, Config, Editor};
 
 use completer::{collect_path_commands, ShellHelper};
-use shell::Shell;
+use shell::{Flow, Options, Shell};
 
 fn home_dir() -> Option<PathBuf> {
-    env::var_os("HOME")
-        .or_else(|| env::var_os("USERPROFILE"))
-        .map(PathBuf::from)
+    env::var_os("HOME").map(PathBuf::from)
 }
 
 fn history_path() -> PathBuf {
@@ -28,15 +38,205 @@ fn history_path() -> PathBuf {
         .join(".shell_history")
 }
 
+const USAGE: &str = "usage: rsh [-efilnsuvxC] [-o option] [--login] [--norc] [--noprofile] [--rcfile file] [--posix]\n           [-c command [name [arg ...]]] [file [arg ...]]";
+
+/// Which startup files to run, from the command line.
+struct Startup {
+    login: bool,
+    profile: bool,
+    rc: bool,
+    rc_file: Option<PathBuf>,
+    posix: bool,
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.get(1).map(String::as_str) == Some("--version") {
-        println!("rsh v0.1.2");
-        return;
+    let mut shell = Shell::new();
+
+    let mut command = false;
+    let mut read_stdin = false;
+    let mut interactive = false;
+    let mut startup = Startup {
+        login: args.first().is_some_and(|arg| arg.starts_with('-')),
+        profile: true,
+        rc: true,
+        rc_file: None,
+        posix: false,
+    };
+    let mut rest = args.get(1..).unwrap_or_default();
+    while let Some(arg) = rest.first() {
+        match arg.as_str() {
+            "--version" => {
+                println!("rsh v0.1.2");
+                return;
+            }
+            "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            "--login" => startup.login = true,
+            "--noprofile" => startup.profile = false,
+            "--norc" => startup.rc = false,
+            "--posix" => startup.posix = true,
+            "--rcfile" => {
+                let Some(file) = rest.get(1) else {
+                    eprintln!("rsh: --rcfile: option requires an argument");
+                    process::exit(2);
+                };
+                startup.rc_file = Some(PathBuf::from(file));
+                rest = &rest[1..];
+            }
+            "--" => {
+                rest = &rest[1..];
+                break;
+            }
+            "-" => {
+                rest = &rest[1..];
+                read_stdin = true;
+                break;
+            }
+            flags if flags.starts_with('-') && !flags.starts_with("--") => {
+                for flag in flags[1..].chars() {
+                    match flag {
+                        'c' => command = true,
+                        's' => read_stdin = true,
+                        'i' => interactive = true,
+                        'l' => startup.login = true,
+                        'o' => {
+                            let Some(name) = rest.get(1) else {
+                                eprintln!("rsh: -o: option requires an argument");
+                                process::exit(2);
+                            };
+                            let Some(value) = shell.options.get_mut(name) else {
+                                eprintln!("rsh: {}: invalid option name", name);
+                                process::exit(2);
+                            };
+                            *value = true;
+                            rest = &rest[1..];
+                        }
+                        _ if let Some(name) = Options::by_letter(flag) => {
+                            if let Some(value) = shell.options.get_mut(name) {
+                                *value = true;
+                            }
+                        }
+                        _ => {
+                            eprintln!("rsh: -{}: invalid option\n{}", flag, USAGE);
+                            process::exit(2);
+                        }
+                    }
+                }
+            }
+            long if long.starts_with("--") => {
+                eprintln!("rsh: {}: invalid option\n{}", long, USAGE);
+                process::exit(2);
+            }
+            _ => break,
+        }
+        rest = &rest[1..];
     }
 
-    let mut shell = Shell::new();
+    let source = if command {
+        let Some(source) = rest.first() else {
+            eprintln!("rsh: -c: option requires an argument");
+            process::exit(2);
+        };
+        if let Some(name) = rest.get(1) {
+            shell.arg0 = name.clone();
+        }
+        shell.positional = rest.iter().skip(2).cloned().collect();
+        Some(source.clone())
+    } else {
+        None
+    };
+    let file = match rest.first() {
+        Some(file) if !command && !read_stdin => {
+            shell.arg0 = file.clone();
+            shell.positional = rest[1..].to_vec();
+            Some(file.clone())
+        }
+        _ => None,
+    };
+    if source.is_none() && file.is_none() {
+        shell.positional = rest.to_vec();
+        if interactive || (sys::isatty(0) && sys::isatty(2)) {
+            shell.interactive = true;
+            shell.shopts.expand_aliases = true;
+            shell.terminal = jobs::Terminal::init();
+        }
+    }
 
+    run_startup(&startup, &mut shell);
+    if let Some(Flow::Exit(code)) = shell.flow {
+        io::stdout().flush().ok();
+        process::exit(code);
+    }
+
+    let code = if let Some(source) = source {
+        script::run_source(&source, "-c", &mut shell)
+    } else if let Some(file) = file {
+        match fs::read_to_string(&file) {
+            Ok(source) => script::run_source(&source, &file, &mut shell),
+            Err(e) => {
+                eprintln!("rsh: {}: {}", file, e);
+                127
+            }
+        }
+    } else if shell.interactive {
+        repl(&mut shell)
+    } else {
+        script::run_stdin("stdin", &mut shell)
+    };
+    io::stdout().flush().ok();
+    process::exit(code);
+}
+
+/// Run the startup files: the system and user profiles for a login shell,
+/// then for an interactive shell `~/.rshrc`, or in POSIX mode the file
+/// named by `$ENV`. Missing files are skipped, and errors in a file are
+/// reported without stopping startup. Stops early if a file runs `exit`.
+fn run_startup(startup: &Startup, shell: &mut Shell) {
+    let mut files = Vec::new();
+    if startup.login && startup.profile {
+        files.push(PathBuf::from("/etc/rsh/profile"));
+        files.extend(home_dir().map(|home| home.join(".rsh_profile")));
+    }
+    if shell.interactive && startup.rc {
+        if startup.posix {
+            files.extend(env_file(shell));
+        } else if let Some(file) = &startup.rc_file {
+            files.push(file.clone());
+        } else {
+            files.extend(home_dir().map(|home| home.join(".rshrc")));
+        }
+    }
+
+    for file in files {
+        if startup.rc_file.as_ref() != Some(&file) && !file.is_file() {
+            continue;
+        }
+        script::run_file(&file, true, shell);
+        if shell.flow.is_some() {
+            break;
+        }
+    }
+}
+
+/// The value of `$ENV` after parameter expansion, if it is set.
+fn env_file(shell: &mut Shell) -> Option<PathBuf> {
+    let value = shell.vars.get("ENV")?.to_string();
+    let expanded = match lexer::tokenize(&value) {
+        Ok(tokens) => match tokens.as_slice() {
+            [(lexer::Token::Word(word), _)] => expand::expand_word(word, shell).ok()?,
+            _ => value,
+        },
+        Err(_) => value,
+    };
+    Some(PathBuf::from(expanded)).filter(|path| !path.as_os_str().is_empty())
+}
+
+/// Read commands with the line editor until `exit` or end of input.
+/// Returns the exit status.
+fn repl(shell: &mut Shell) -> i32 {
     let path_commands = collect_path_commands();
     let helper = ShellHelper::new(path_commands);
 
@@ -52,33 +252,72 @@ fn main() {
     let hist = history_path();
     let _ = rl.load_history(&hist);
 
+    // Lines of a command that still needs more input.
+    let mut buffer = String::new();
+
     loop {
-        let prompt = prompt::build();
-        let colored = prompt::build_colored();
+        if buffer.is_empty() {
+            shell.jobs.report_changes();
+        }
+        let (prompt, colored) = if buffer.is_empty() {
+            (prompt::build(), prompt::build_colored())
+        } else {
+            let ps2 = prompt::continuation(shell);
+            (ps2.clone(), ps2)
+        };
         if let Some(h) = rl.helper_mut() {
             h.colored_prompt = colored;
         }
 
         match rl.readline(&prompt) {
             Ok(line) => {
-                let trimmed = line.trim();
-                if trimmed.is_empty() {
+                if !buffer.is_empty() {
+                    buffer.push('\n');
+                }
+                buffer.push_str(&line);
+                if shell.options.verbose {
+                    eprintln!("{}", line);
+                }
+                let source = buffer.trim();
+                if source.is_empty() {
+                    buffer.clear();
                     continue;
                 }
-                rl.add_history_entry(trimmed).ok();
 
-                let tokens = lexer::tokenize(trimmed, shell.last_exit_code);
-                let list = parser::parse(tokens);
+                let result = shell.parse(source);
+                if matches!(&result, Err(e) if e.incomplete) {
+                    continue;
+                }
+                rl.add_history_entry(source).ok();
 
-                if !executor::run_list(list, &mut shell) {
+                let list = match result {
+                    Ok(list) => list,
+                    Err(e) => {
+                        eprintln!("rsh: {}", e.render(source));
+                        shell.last_exit_code = 2;
+                        buffer.clear();
+                        continue;
+                    }
+                };
+                buffer.clear();
+
+                sys::set_interrupted(false);
+                if !executor::run_list(&list, shell) {
                     break;
                 }
             }
             Err(ReadlineError::Interrupted) => {
                 println!("^C");
+                buffer.clear();
                 continue;
             }
             Err(ReadlineError::Eof) => {
+                if !buffer.is_empty() {
+                    if let Err(e) = parser::parse(buffer.trim()) {
+                        eprintln!("rsh: {}", e.render(buffer.trim()));
+                    }
+                    rl.add_history_entry(buffer.trim()).ok();
+                }
                 println!("exit");
                 break;
             }
@@ -90,4 +329,5 @@ fn main() {
     }
 
     rl.save_history(&hist).ok();
+    shell.last_exit_code
 }
@@ -1,83 +1,841 @@
-use crate::lexer::Token;
-
-/// One command with optional redirections.
-#[derive(Debug, Clone, Default)]
-pub struct SimpleCmd {
-    pub argv: Vec<String>,
-    pub stdin_file: Option<String>,
-    pub stdout_file: Option<String>,
-    pub append: bool,
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::{
+    AndOr, AndOrOp, Assignment, CaseItem, CaseTerminator, Command, CompoundCmd, FunctionDef, List, Pipeline, Redirect,
+    RedirectKind, SimpleCmd,
+};
+use crate::lexer::{self, Span, Token, Word, WordPart};
+
+/// A syntax error, located in the source it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub span: Span,
+    /// 1-based line and column of `span.start`.
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    /// What the parser was looking for, e.g. "a filename".
+    pub expected: Option<String>,
+    /// The input ended too early; more lines could complete it.
+    pub incomplete: bool,
+}
+
+impl ParseError {
+    pub fn new(source: &str, span: Span, message: String, expected: Option<String>) -> Self {
+        let before = &source[..span.start.min(source.len())];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let column = before[line_start..].chars().count() + 1;
+        ParseError { span, line, column, message, expected, incomplete: false }
+    }
+
+    /// Mark the error as caused by running out of input.
+    pub fn incomplete(mut self) -> Self {
+        self.incomplete = true;
+        self
+    }
+
+    /// The error followed by the offending source line and a caret marker.
+    pub fn render(&self, source: &str) -> String {
+        let start = self.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = source[start..].find('\n').map(|i| start + i).unwrap_or(source.len());
+        let text = &source[line_start..line_end];
+        let end = self.span.end.clamp(start, line_end);
+        let width = source[start..end].chars().count().max(1);
+        format!(
+            "{}\n  {}\n  {}{}",
+            self,
+            text,
+            " ".repeat(self.column - 1),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)?;
+        if let Some(expected) = &self.expected {
+            write!(f, " (expected {})", expected)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a full command line or script into a list.
+pub fn parse(source: &str) -> Result<List, ParseError> {
+    parse_with_aliases(source, &HashMap::new())
 }
 
-/// A sequence of commands connected by pipes.
-pub type Pipeline = Vec<SimpleCmd>;
+/// Parse source, replacing the command words that are aliases with their
+/// values.
+pub fn parse_with_aliases(source: &str, aliases: &HashMap<String, String>) -> Result<List, ParseError> {
+    parse_tokens(source, lexer::tokenize_with_aliases(source, aliases)?, aliases)
+}
 
-/// How a pipeline is conditionally connected to the previous one.
-#[derive(Debug, Clone)]
-pub enum RunIf {
-    Always,    // first, or after ;
-    OnSuccess, // after &&
-    OnFailure, // after ||
+/// Parse tokens lexed from `source`, which their spans point into.
+pub fn parse_tokens(
+    source: &str,
+    tokens: Vec<(Token, Span)>,
+    aliases: &HashMap<String, String>,
+) -> Result<List, ParseError> {
+    let mut parser = Parser { source, tokens, pos: 0, aliases, expanding: Vec::new(), alias_next: None };
+    let list = parser.list()?;
+    match parser.peek() {
+        None => Ok(list),
+        Some(_) => Err(parser.error(None)),
+    }
 }
 
-/// A pipeline with its run condition.
-pub type CommandList = Vec<(Pipeline, RunIf)>;
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(Token, Span)>,
+    pos: usize,
+    aliases: &'a HashMap<String, String>,
+    /// Aliases whose values have been put in place of their names, with
+    /// the position just past the value's tokens. An alias is not expanded
+    /// again inside its own value.
+    expanding: Vec<(String, usize)>,
+    /// The word after an alias whose value ends in a blank, which is
+    /// checked for an alias too.
+    alias_next: Option<usize>,
+}
 
-pub fn parse(tokens: Vec<Token>) -> CommandList {
-    let mut list: CommandList = Vec::new();
-    let mut pipeline: Pipeline = Vec::new();
-    let mut cmd = SimpleCmd::default();
-    let mut run_if = RunIf::Always;
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(tok, _)| tok)
+    }
 
-    let flush_cmd = |cmd: &mut SimpleCmd, pipeline: &mut Pipeline| {
-        if !cmd.argv.is_empty() || cmd.stdin_file.is_some() {
-            pipeline.push(std::mem::take(cmd));
+    fn next(&mut self) -> Option<Token> {
+        let tok = self.peek().cloned();
+        self.pos += 1;
+        tok
+    }
+
+    /// Replace the word at the current position with its alias, and the
+    /// first word of that with its own alias, and so on, as long as the
+    /// aliases are not already being expanded. Returns true if anything
+    /// changed. A value that does not tokenize is a syntax error at the
+    /// alias name.
+    fn expand_alias(&mut self) -> Result<bool, ParseError> {
+        let mut changed = false;
+        loop {
+            self.expanding.retain(|(_, end)| *end > self.pos);
+            let Some((Token::Word(word), span)) = self.tokens.get(self.pos) else { break };
+            let Some(name) = word.as_literal() else { break };
+            if self.expanding.iter().any(|(n, _)| n == name) {
+                break;
+            }
+            let Some(value) = self.aliases.get(name) else { break };
+            let tokens = lexer::tokenize_with_aliases(value, self.aliases).map_err(|e| {
+                ParseError::new(self.source, *span, format!("{}: alias: {}", name, e.message), None)
+            })?;
+
+            // The value's tokens point at the alias name for error messages.
+            let span = *span;
+            let len = tokens.len();
+            let shift = |at: &mut usize| {
+                if *at > self.pos {
+                    *at = *at + len - 1;
+                }
+            };
+            for (_, end) in &mut self.expanding {
+                shift(end);
+            }
+            if let Some(next) = &mut self.alias_next {
+                shift(next);
+            }
+            if value.ends_with([' ', '\t']) {
+                self.alias_next = Some(self.pos + len);
+            }
+            self.expanding.push((name.to_string(), self.pos + len));
+            self.tokens.splice(self.pos..=self.pos, tokens.into_iter().map(|(tok, _)| (tok, span)));
+            changed = true;
         }
-    };
+        Ok(changed)
+    }
 
-    let flush_pipeline = |pipeline: &mut Pipeline, list: &mut CommandList, run_if: &mut RunIf, next: RunIf| {
-        if !pipeline.is_empty() {
-            list.push((std::mem::take(pipeline), std::mem::replace(run_if, next)));
+    /// An error at the next token, or at the end of input.
+    fn error(&self, expected: Option<&str>) -> ParseError {
+        let expected = expected.map(str::to_string);
+        match self.tokens.get(self.pos) {
+            Some((tok, span)) => {
+                let message = format!("syntax error near unexpected token `{}`", describe(tok));
+                ParseError::new(self.source, *span, message, expected)
+            }
+            None => {
+                let end = self.source.trim_end().len();
+                let message = "syntax error: unexpected end of input".to_string();
+                ParseError::new(self.source, Span { start: end, end }, message, expected).incomplete()
+            }
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&Token::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    /// True if the next token is the given reserved word.
+    fn at_reserved(&self, word: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.as_literal() == Some(word))
+    }
+
+    /// True if the next token closes the enclosing list.
+    fn at_list_end(&self) -> bool {
+        match self.peek() {
+            None | Some(Token::RParen | Token::DSemi | Token::SemiAnd | Token::DSemiAnd) => true,
+            Some(Token::Word(w)) => matches!(
+                w.as_literal(),
+                Some("}" | "then" | "elif" | "else" | "fi" | "do" | "done" | "esac")
+            ),
+            _ => false,
+        }
+    }
+
+    fn expect_reserved(&mut self, word: &str) -> Result<(), ParseError> {
+        if self.at_reserved(word) {
+            self.pos += 1;
+            Ok(())
         } else {
-            *run_if = next;
+            Err(self.error(Some(&format!("`{}`", word))))
         }
-    };
+    }
+
+    /// A list that must contain at least one command, such as the body of
+    /// a compound command.
+    fn body(&mut self) -> Result<List, ParseError> {
+        let list = self.list()?;
+        if list.items.is_empty() {
+            return Err(self.error(Some("a command")));
+        }
+        Ok(list)
+    }
 
-    for token in tokens {
-        match token {
-            Token::Word(w) => cmd.argv.push(w),
-            Token::Pipe => {
-                flush_cmd(&mut cmd, &mut pipeline);
+    fn list(&mut self) -> Result<List, ParseError> {
+        let mut list = List::default();
+        loop {
+            self.skip_newlines();
+            if self.at_list_end() {
+                break;
             }
-            Token::Semicolon => {
-                flush_cmd(&mut cmd, &mut pipeline);
-                flush_pipeline(&mut pipeline, &mut list, &mut run_if, RunIf::Always);
+            let mut and_or = self.and_or()?;
+            match self.peek() {
+                Some(Token::Semicolon) | Some(Token::Newline) => self.pos += 1,
+                Some(Token::Amp) => {
+                    self.pos += 1;
+                    and_or.background = true;
+                }
+                _ => {
+                    list.items.push(and_or);
+                    break;
+                }
+            }
+            list.items.push(and_or);
+        }
+        Ok(list)
+    }
+
+    fn and_or(&mut self) -> Result<AndOr, ParseError> {
+        let first = self.pipeline()?;
+        let mut rest = Vec::new();
+        loop {
+            let op = match self.peek() {
+                Some(Token::And) => AndOrOp::And,
+                Some(Token::Or) => AndOrOp::Or,
+                _ => break,
+            };
+            self.pos += 1;
+            self.skip_newlines();
+            rest.push((op, self.pipeline()?));
+        }
+        Ok(AndOr { first, rest, background: false })
+    }
+
+    fn pipeline(&mut self) -> Result<Pipeline, ParseError> {
+        let negated = self.at_reserved("!");
+        if negated {
+            self.pos += 1;
+        }
+        let mut commands = vec![self.command()?];
+        while self.peek() == Some(&Token::Pipe) {
+            self.pos += 1;
+            self.skip_newlines();
+            commands.push(self.command()?);
+        }
+        Ok(Pipeline { commands, negated })
+    }
+
+    fn command(&mut self) -> Result<Command, ParseError> {
+        self.expand_alias()?;
+        let compound = if self.at_reserved("{") {
+            self.pos += 1;
+            let body = self.list()?;
+            self.expect_reserved("}")?;
+            CompoundCmd::BraceGroup(body)
+        } else if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            let body = self.list()?;
+            if self.peek() != Some(&Token::RParen) {
+                return Err(self.error(Some("`)`")));
+            }
+            self.pos += 1;
+            CompoundCmd::Subshell(body)
+        } else if let Some(Token::Arith(expr)) = self.peek() {
+            let expr = expr.clone();
+            self.pos += 1;
+            CompoundCmd::Arith(expr)
+        } else if self.at_reserved("if") {
+            self.if_clause()?
+        } else if self.at_reserved("while") || self.at_reserved("until") {
+            let until = self.at_reserved("until");
+            self.pos += 1;
+            let condition = self.body()?;
+            let body = self.do_group()?;
+            CompoundCmd::While { until, condition, body }
+        } else if self.at_reserved("for") {
+            self.for_clause()?
+        } else if self.at_reserved("case") {
+            self.case_clause()?
+        } else if self.at_reserved("function") {
+            self.pos += 1;
+            let name = match self.peek() {
+                Some(Token::Word(w)) => w.as_literal().map(str::to_string),
+                _ => None,
+            };
+            let Some(name) = name else { return Err(self.error(Some("a function name"))) };
+            self.pos += 1;
+            if self.peek() == Some(&Token::LParen) {
+                self.pos += 1;
+                if self.peek() != Some(&Token::RParen) {
+                    return Err(self.error(Some("`)`")));
+                }
+                self.pos += 1;
+            }
+            return self.function_body(name);
+        } else if let Some(name) = self.function_name() {
+            self.pos += 3;
+            return self.function_body(name);
+        } else {
+            return self.simple_command().map(Command::Simple);
+        };
+
+        let mut redirects = Vec::new();
+        while let Some(redirect) = self.redirect()? {
+            redirects.push(redirect);
+        }
+        Ok(Command::Compound(compound, redirects))
+    }
+
+    /// The name of a `name()` function definition, if one starts here.
+    fn function_name(&self) -> Option<String> {
+        let name = match self.tokens.get(self.pos) {
+            Some((Token::Word(w), _)) => w.as_literal()?,
+            _ => return None,
+        };
+        let open = self.tokens.get(self.pos + 1).map(|(tok, _)| tok);
+        let close = self.tokens.get(self.pos + 2).map(|(tok, _)| tok);
+        (open == Some(&Token::LParen) && close == Some(&Token::RParen)).then(|| name.to_string())
+    }
+
+    /// The compound command that makes up a function's body.
+    fn function_body(&mut self, name: String) -> Result<Command, ParseError> {
+        self.skip_newlines();
+        let start = self.pos;
+        match self.command()? {
+            body @ Command::Compound(..) => Ok(Command::Function(FunctionDef { name, body: Rc::new(body) })),
+            _ => {
+                self.pos = start;
+                Err(self.error(Some("a compound command")))
             }
-            Token::And => {
-                flush_cmd(&mut cmd, &mut pipeline);
-                flush_pipeline(&mut pipeline, &mut list, &mut run_if, RunIf::OnSuccess);
+        }
+    }
+
+    fn if_clause(&mut self) -> Result<CompoundCmd, ParseError> {
+        let mut branches = Vec::new();
+        let mut otherwise = None;
+        self.pos += 1;
+        loop {
+            let condition = self.body()?;
+            self.expect_reserved("then")?;
+            branches.push((condition, self.body()?));
+            if self.at_reserved("elif") {
+                self.pos += 1;
+                continue;
             }
-            Token::Or => {
-                flush_cmd(&mut cmd, &mut pipeline);
-                flush_pipeline(&mut pipeline, &mut list, &mut run_if, RunIf::OnFailure);
+            if self.at_reserved("else") {
+                self.pos += 1;
+                otherwise = Some(self.body()?);
             }
-            Token::RedirectOut(f) => {
-                cmd.stdout_file = Some(f);
-                cmd.append = false;
+            break;
+        }
+        self.expect_reserved("fi")?;
+        Ok(CompoundCmd::If { branches, otherwise })
+    }
+
+    /// `do list done`
+    fn do_group(&mut self) -> Result<List, ParseError> {
+        self.expect_reserved("do")?;
+        let body = self.body()?;
+        self.expect_reserved("done")?;
+        Ok(body)
+    }
+
+    fn for_clause(&mut self) -> Result<CompoundCmd, ParseError> {
+        self.pos += 1;
+        if let Some(Token::Arith(expr)) = self.peek() {
+            let [init, condition, step] = <[Word; 3]>::try_from(split_arith(expr))
+                .map_err(|_| self.error(Some("`((init; condition; step))`")))?;
+            self.pos += 1;
+            if self.peek() == Some(&Token::Semicolon) {
+                self.pos += 1;
             }
-            Token::RedirectAppend(f) => {
-                cmd.stdout_file = Some(f);
-                cmd.append = true;
+            self.skip_newlines();
+            let body = self.do_group()?;
+            return Ok(CompoundCmd::ArithFor { init, condition, step, body });
+        }
+
+        let name = match self.peek() {
+            Some(Token::Word(w)) => w.as_literal().filter(|n| is_name(n)).map(str::to_string),
+            _ => None,
+        };
+        let Some(name) = name else { return Err(self.error(Some("a variable name"))) };
+        self.pos += 1;
+
+        self.skip_newlines();
+        let mut words = None;
+        if self.at_reserved("in") {
+            self.pos += 1;
+            let mut list = Vec::new();
+            while let Some(Token::Word(w)) = self.peek() {
+                list.push(w.clone());
+                self.pos += 1;
             }
-            Token::RedirectIn(f) => {
-                cmd.stdin_file = Some(f);
+            match self.peek() {
+                Some(Token::Semicolon) | Some(Token::Newline) => self.pos += 1,
+                _ => return Err(self.error(Some("`;` or a newline"))),
             }
+            words = Some(list);
+        } else if self.peek() == Some(&Token::Semicolon) {
+            self.pos += 1;
         }
+        self.skip_newlines();
+        let body = self.do_group()?;
+        Ok(CompoundCmd::For { name, words, body })
+    }
+
+    fn case_clause(&mut self) -> Result<CompoundCmd, ParseError> {
+        self.pos += 1;
+        let word = match self.next() {
+            Some(Token::Word(w)) => w,
+            _ => {
+                self.pos -= 1;
+                return Err(self.error(Some("a word")));
+            }
+        };
+        self.skip_newlines();
+        self.expect_reserved("in")?;
+
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.at_reserved("esac") {
+                break;
+            }
+            if self.peek() == Some(&Token::LParen) {
+                self.pos += 1;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                match self.next() {
+                    Some(Token::Word(w)) => patterns.push(w),
+                    _ => {
+                        self.pos -= 1;
+                        return Err(self.error(Some("a pattern")));
+                    }
+                }
+                match self.peek() {
+                    Some(Token::Pipe) => self.pos += 1,
+                    Some(Token::RParen) => break,
+                    _ => return Err(self.error(Some("`)`"))),
+                }
+            }
+            self.pos += 1;
+
+            let body = self.list()?;
+            let terminator = match self.peek() {
+                Some(Token::DSemi) => CaseTerminator::Break,
+                Some(Token::SemiAnd) => CaseTerminator::FallThrough,
+                Some(Token::DSemiAnd) => CaseTerminator::Continue,
+                _ if self.at_reserved("esac") => {
+                    items.push(CaseItem { patterns, body, terminator: CaseTerminator::Break });
+                    break;
+                }
+                _ => return Err(self.error(Some("`;;`"))),
+            };
+            self.pos += 1;
+            items.push(CaseItem { patterns, body, terminator });
+        }
+        self.expect_reserved("esac")?;
+        Ok(CompoundCmd::Case { word, items })
+    }
+
+    fn simple_command(&mut self) -> Result<SimpleCmd, ParseError> {
+        let mut cmd = SimpleCmd::default();
+        loop {
+            if let Some(redirect) = self.redirect()? {
+                cmd.redirects.push(redirect);
+                continue;
+            }
+            match self.peek() {
+                Some(Token::Word(w)) => {
+                    // The command name after assignments, and a word after
+                    // an alias ending in a blank, may be aliases too.
+                    let command_name = cmd.words.is_empty() && assignment(w).is_none();
+                    if (command_name || self.alias_next == Some(self.pos)) && self.expand_alias()? {
+                        continue;
+                    }
+                    if self.alias_next == Some(self.pos) {
+                        self.alias_next = None;
+                    }
+                    if let Some(Token::Word(w)) = self.next() {
+                        // `name=value` words count as assignments until the
+                        // command name.
+                        match assignment(&w).filter(|_| cmd.words.is_empty()) {
+                            Some(assignment) => cmd.assignments.push(assignment),
+                            None => cmd.words.push(w),
+                        }
+                    }
+                }
+                _ => break,
+            }
+        }
+        if cmd.words.is_empty() && cmd.redirects.is_empty() && cmd.assignments.is_empty() {
+            return Err(self.error(Some("a command")));
+        }
+        Ok(cmd)
+    }
+
+    /// Parse a redirection operator and its target, if one is next.
+    fn redirect(&mut self) -> Result<Option<Redirect>, ParseError> {
+        let fd = match self.peek() {
+            Some(&Token::IoNumber(n)) => {
+                self.pos += 1;
+                Some(n)
+            }
+            _ => None,
+        };
+        let kind = match self.peek() {
+            Some(Token::Less) => RedirectKind::In,
+            Some(Token::Great) => RedirectKind::Out,
+            Some(Token::DGreat) => RedirectKind::Append,
+            Some(Token::Clobber) => RedirectKind::Clobber,
+            Some(Token::LessGreat) => RedirectKind::ReadWrite,
+            Some(Token::LessAnd) => RedirectKind::DupIn,
+            Some(Token::GreatAnd) => RedirectKind::DupOut,
+            Some(Token::DLess(_)) => RedirectKind::HereDoc,
+            Some(Token::TLess) => RedirectKind::HereString,
+            Some(Token::AndGreat) if fd.is_none() => RedirectKind::OutErr,
+            Some(Token::AndDGreat) if fd.is_none() => RedirectKind::AppendErr,
+            _ if fd.is_some() => return Err(self.error(Some("a redirection operator"))),
+            _ => return Ok(None),
+        };
+        let here_doc = match self.next() {
+            Some(Token::DLess(body)) => body,
+            _ => None,
+        };
+        match self.next() {
+            Some(Token::Word(target)) => Ok(Some(Redirect { fd, kind, target, here_doc })),
+            _ => {
+                self.pos -= 1;
+                let expected = match kind {
+                    RedirectKind::DupIn | RedirectKind::DupOut => "a file descriptor or filename",
+                    RedirectKind::HereDoc => "a here-document delimiter",
+                    RedirectKind::HereString => "a word",
+                    _ => "a filename",
+                };
+                Err(self.error(Some(expected)))
+            }
+        }
+    }
+}
+
+fn describe(tok: &Token) -> String {
+    match tok {
+        Token::Word(w) => word_text(w),
+        Token::Pipe => "|".into(),
+        Token::And => "&&".into(),
+        Token::Or => "||".into(),
+        Token::Semicolon => ";".into(),
+        Token::DSemi => ";;".into(),
+        Token::SemiAnd => ";&".into(),
+        Token::DSemiAnd => ";;&".into(),
+        Token::Amp => "&".into(),
+        Token::Newline => "newline".into(),
+        Token::LParen => "(".into(),
+        Token::RParen => ")".into(),
+        Token::Arith(expr) => format!("(({}))", expr),
+        Token::Less => "<".into(),
+        Token::Great => ">".into(),
+        Token::DGreat => ">>".into(),
+        Token::LessGreat => "<>".into(),
+        Token::LessAnd => "<&".into(),
+        Token::GreatAnd => ">&".into(),
+        Token::Clobber => ">|".into(),
+        Token::AndGreat => "&>".into(),
+        Token::AndDGreat => "&>>".into(),
+        Token::DLess(_) => "<<".into(),
+        Token::TLess => "<<<".into(),
+        Token::IoNumber(n) => n.to_string(),
+    }
+}
+
+/// Split the expression of an arithmetic `for` at its `;`s, trimming the
+/// blanks around each part.
+fn split_arith(expr: &Word) -> Vec<Word> {
+    let mut words = vec![Word::default()];
+    for part in &expr.parts {
+        match part {
+            WordPart::Literal(text) => {
+                for (i, piece) in text.split(';').enumerate() {
+                    if i > 0 {
+                        words.push(Word::default());
+                    }
+                    if !piece.is_empty() {
+                        words.last_mut().unwrap().parts.push(WordPart::Literal(piece.to_string()));
+                    }
+                }
+            }
+            _ => words.last_mut().unwrap().parts.push(part.clone()),
+        }
+    }
+    for word in &mut words {
+        if let Some(WordPart::Literal(first)) = word.parts.first_mut() {
+            *first = first.trim_start().to_string();
+        }
+        if let Some(WordPart::Literal(last)) = word.parts.last_mut() {
+            *last = last.trim_end().to_string();
+        }
+        word.parts.retain(|p| !matches!(p, WordPart::Literal(s) if s.is_empty()));
+    }
+    words
+}
+
+/// Split an assignment word `name=value` into its name and value.
+fn assignment(word: &Word) -> Option<Assignment> {
+    let Some(WordPart::Literal(first)) = word.parts.first() else { return None };
+    let (name, rest) = first.split_once('=')?;
+    if !is_name(name) {
+        return None;
+    }
+    let mut parts = word.parts[1..].to_vec();
+    if !rest.is_empty() {
+        parts.insert(0, WordPart::Literal(rest.to_string()));
+    }
+    Some(Assignment { name: name.to_string(), value: Word { parts } })
+}
+
+/// True if `s` can be a variable name.
+pub fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn word_text(w: &Word) -> String {
+    w.as_literal().map(str::to_string).unwrap_or_else(|| "word".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> ParseError {
+        parse(source).expect_err("should not parse")
+    }
+
+    #[test]
+    fn unfinished_input_is_incomplete() {
+        let sources = [
+            "echo \"abc",
+            "echo 'x",
+            "if true; then",
+            "while true; do echo",
+            "echo a |",
+            "foo &&",
+            "case x in",
+            "f() {",
+            "ls $(echo",
+            "cat <<EOF\nbody",
+        ];
+        for source in sources {
+            assert!(error(source).incomplete, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn wrong_input_is_not_incomplete() {
+        for source in ["echo (", "echo a; ;", "fi", "then echo", "echo )", "if true; then echo; done"] {
+            assert!(!error(source).incomplete, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn unexpected_token_position() {
+        let e = error("echo a; ;");
+        assert_eq!(e.message, "syntax error near unexpected token `;`");
+        assert_eq!(e.span, Span { start: 8, end: 9 });
+        assert_eq!((e.line, e.column), (1, 9));
+        assert_eq!(e.expected.as_deref(), Some("a command"));
+
+        let e = error("echo ok\n  if then fi");
+        assert_eq!(e.message, "syntax error near unexpected token `then`");
+        assert_eq!(e.span, Span { start: 13, end: 17 });
+        assert_eq!((e.line, e.column), (2, 6));
+    }
+
+    #[test]
+    fn end_of_input_position() {
+        let e = error("cat <");
+        assert_eq!(e.message, "syntax error: unexpected end of input");
+        assert_eq!(e.expected.as_deref(), Some("a filename"));
+        assert_eq!((e.line, e.column), (1, 6));
+    }
+
+    #[test]
+    fn render_marks_the_token() {
+        let source = "echo ok\necho (x)";
+        assert_eq!(
+            error(source).render(source),
+            "line 2, column 6: syntax error near unexpected token `(`\n  echo (x)\n       ^"
+        );
+        let source = "echo a; if then fi";
+        assert_eq!(
+            error(source).render(source),
+            "line 1, column 12: syntax error near unexpected token `then` (expected a command)\n  echo a; if then fi\n             ^^^^"
+        );
+        let source = "echo \"abc";
+        assert_eq!(error(source).render(source), "line 1, column 6: unterminated double quote\n  echo \"abc\n       ^^^^");
+    }
+
+    fn ok(source: &str) -> List {
+        parse(source).expect("should parse")
+    }
+
+    fn text(word: &Word) -> String {
+        match word.parts.as_slice() {
+            [WordPart::Literal(s)] => s.clone(),
+            parts => panic!("not a plain word: {:?}", parts),
+        }
+    }
+
+    /// The words of a simple command.
+    fn words(cmd: &Command) -> Vec<String> {
+        match cmd {
+            Command::Simple(simple) => simple.words.iter().map(text).collect(),
+            other => panic!("not a simple command: {:?}", other),
+        }
+    }
+
+    /// The words of each command in a pipeline.
+    fn stages(pipeline: &Pipeline) -> Vec<Vec<String>> {
+        pipeline.commands.iter().map(words).collect()
+    }
+
+    #[test]
+    fn and_or_lists() {
+        let list = ok("a && b || c; d & e");
+        assert_eq!(list.items.len(), 3);
+        let first = &list.items[0];
+        assert_eq!(stages(&first.first), [["a"]]);
+        let ops: Vec<_> = first.rest.iter().map(|(op, _)| *op).collect();
+        assert_eq!(ops, [AndOrOp::And, AndOrOp::Or]);
+        assert_eq!(stages(&first.rest[0].1), [["b"]]);
+        assert_eq!(stages(&first.rest[1].1), [["c"]]);
+        assert!(!first.background);
+        assert!(list.items[1].background && list.items[1].rest.is_empty());
+        assert!(!list.items[2].background);
+        assert_eq!(stages(&list.items[2].first), [["e"]]);
+    }
+
+    #[test]
+    fn pipes_bind_tighter_than_and_or() {
+        let list = ok("a | b x && ! c | d\ne");
+        assert_eq!(list.items.len(), 2);
+        let and_or = &list.items[0];
+        assert_eq!(stages(&and_or.first), [vec!["a"], vec!["b", "x"]]);
+        assert!(!and_or.first.negated);
+        let (op, second) = &and_or.rest[0];
+        assert_eq!(*op, AndOrOp::And);
+        assert!(second.negated);
+        assert_eq!(stages(second), [["c"], ["d"]]);
+    }
+
+    #[test]
+    fn background_takes_the_whole_and_or_list() {
+        let list = ok("a && b & c");
+        assert_eq!(list.items.len(), 2);
+        assert!(list.items[0].background);
+        assert_eq!(list.items[0].rest.len(), 1);
+        assert!(!list.items[1].background);
+    }
+
+    #[test]
+    fn subshells_and_groups() {
+        let list = ok("(a; b) | { c && d; } || e");
+        let pipeline = &list.items[0].first;
+        let [Command::Compound(CompoundCmd::Subshell(sub), _), Command::Compound(CompoundCmd::BraceGroup(group), _)] =
+            pipeline.commands.as_slice()
+        else {
+            panic!("{:?}", pipeline);
+        };
+        assert_eq!(sub.items.len(), 2);
+        assert_eq!(group.items.len(), 1);
+        assert_eq!(group.items[0].rest.len(), 1);
+        assert_eq!(list.items[0].rest[0].0, AndOrOp::Or);
+
+        let list = ok("((a) )");
+        let Command::Compound(CompoundCmd::Subshell(outer), _) = &list.items[0].first.commands[0] else {
+            panic!("{:?}", list);
+        };
+        assert!(matches!(outer.items[0].first.commands[0], Command::Compound(CompoundCmd::Subshell(_), _)));
+    }
+
+    #[test]
+    fn redirects_attach_to_their_command() {
+        let list = ok("a >out x 2>&1 | b <in");
+        let [Command::Simple(a), Command::Simple(b)] = list.items[0].first.commands.as_slice() else {
+            panic!("{:?}", list);
+        };
+        assert_eq!(a.words.iter().map(text).collect::<Vec<_>>(), ["a", "x"]);
+        let redirects: Vec<_> = a.redirects.iter().map(|r| (r.fd, r.kind, text(&r.target))).collect();
+        assert_eq!(
+            redirects,
+            [(None, RedirectKind::Out, "out".to_string()), (Some(2), RedirectKind::DupOut, "1".to_string())]
+        );
+        assert_eq!(b.redirects.len(), 1);
+        assert_eq!((b.redirects[0].kind, b.redirects[0].source_fd()), (RedirectKind::In, 0));
+
+        let list = ok("{ a >inner; } >>outer");
+        let Command::Compound(CompoundCmd::BraceGroup(body), redirects) = &list.items[0].first.commands[0] else {
+            panic!("{:?}", list);
+        };
+        assert_eq!(redirects.len(), 1);
+        assert_eq!((redirects[0].kind, text(&redirects[0].target)), (RedirectKind::Append, "outer".to_string()));
+        let Command::Simple(inner) = &body.items[0].first.commands[0] else { panic!("{:?}", body) };
+        assert_eq!(text(&inner.redirects[0].target), "inner");
     }
 
-    flush_cmd(&mut cmd, &mut pipeline);
-    if !pipeline.is_empty() {
-        list.push((pipeline, run_if));
+    #[test]
+    fn assignments_come_before_the_command() {
+        let list = ok("x=1 y=2 cmd z=3");
+        let Command::Simple(cmd) = &list.items[0].first.commands[0] else { panic!("{:?}", list) };
+        let names: Vec<_> = cmd.assignments.iter().map(|a| (a.name.as_str(), text(&a.value))).collect();
+        assert_eq!(names, [("x", "1".to_string()), ("y", "2".to_string())]);
+        assert_eq!(cmd.words.iter().map(text).collect::<Vec<_>>(), ["cmd", "z=3"]);
     }
-    list
 }
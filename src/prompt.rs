@@ -1,6 +1,8 @@
 use std::env;
 use std::process::{Command, Stdio};
 
+use crate::shell::Shell;
+
 fn cwd() -> String {
     env::current_dir()
         .map(|p| {
@@ -32,6 +34,11 @@ pub fn build_colored() -> String {
     }
 }
 
+/// Continuation prompt shown while a command is still incomplete.
+pub fn continuation(shell: &Shell) -> String {
+    shell.vars.get("PS2").unwrap_or("> ").to_string()
+}
+
 fn git_branch() -> Option<String> {
     let out = Command::new("git")
         .args(["rev-parse", "--abbrev-ref", "HEAD"])
@@ -0,0 +1,125 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::executor;
+use crate::parser;
+use crate::shell::{Flow, Shell};
+use crate::sys;
+
+/// Run shell source, such as a script file or a `-c` string, without the
+/// line editor. Errors are reported with `name`. Returns the status of the
+/// last command.
+pub fn run_source(source: &str, name: &str, shell: &mut Shell) -> i32 {
+    run_lines(source.lines().map(|line| Ok(line.to_string())), name, false, shell)
+}
+
+/// Run a file of commands, reporting errors with its path. A startup file
+/// goes on past a syntax error, with the next command after it. Returns
+/// `None` if the file could not be read.
+pub fn run_file(path: &Path, startup: bool, shell: &mut Shell) -> Option<i32> {
+    let name = path.display().to_string();
+    match fs::read_to_string(path) {
+        Ok(source) => Some(run_lines(source.lines().map(|line| Ok(line.to_string())), &name, startup, shell)),
+        Err(e) => {
+            eprintln!("rsh: {}: {}", name, e);
+            None
+        }
+    }
+}
+
+/// Run commands read from standard input a line at a time. Nothing past
+/// the current line is read, so commands can read the rest themselves.
+pub fn run_stdin(name: &str, shell: &mut Shell) -> i32 {
+    run_lines(std::iter::from_fn(|| read_line(&mut sys::FdReader(0))), name, false, shell)
+}
+
+/// Run each command as soon as its last line has been read, so a syntax
+/// error stops the input only from that command on, or with `resume` only
+/// skips that command. `exit`, and `return` in a sourced file, stop it
+/// too. Errors while it runs are reported with `name` and the line.
+fn run_lines(lines: impl Iterator<Item = io::Result<String>>, name: &str, resume: bool, shell: &mut Shell) -> i32 {
+    let outer = shell.location.take();
+    let code = run_commands(lines, name, resume, shell);
+    shell.location = outer;
+    code
+}
+
+fn run_commands(lines: impl Iterator<Item = io::Result<String>>, name: &str, resume: bool, shell: &mut Shell) -> i32 {
+    let mut buffer = String::new();
+    let mut first_line = 1;
+    for (n, line) in lines.enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("rsh: {}: {}", name, e);
+                return 1;
+            }
+        };
+        if shell.options.verbose {
+            eprintln!("{}", line);
+        }
+        if buffer.is_empty() {
+            first_line = n + 1;
+        } else {
+            buffer.push('\n');
+        }
+        buffer.push_str(&line);
+        if buffer.trim().is_empty() {
+            buffer.clear();
+            continue;
+        }
+
+        let list = match shell.parse(&buffer) {
+            Ok(list) => list,
+            Err(e) if e.incomplete => continue,
+            Err(e) if resume => {
+                syntax_error(e, first_line, &buffer, name, shell);
+                buffer.clear();
+                continue;
+            }
+            Err(e) => return syntax_error(e, first_line, &buffer, name, shell),
+        };
+        buffer.clear();
+        // With `set -n` the commands are only checked.
+        if shell.options.noexec && !shell.interactive {
+            continue;
+        }
+        shell.location = Some((name.to_string(), first_line));
+        executor::run_list(&list, shell);
+        match shell.flow {
+            Some(Flow::Exit(code)) => return code,
+            Some(_) => return shell.last_exit_code,
+            None => {}
+        }
+    }
+    if let Err(e) = shell.parse(&buffer) {
+        return syntax_error(e, first_line, &buffer, name, shell);
+    }
+    shell.last_exit_code
+}
+
+fn syntax_error(mut e: parser::ParseError, first_line: usize, source: &str, name: &str, shell: &mut Shell) -> i32 {
+    e.line += first_line - 1;
+    eprintln!("rsh: {}: {}", name, e.render(source));
+    shell.last_exit_code = 2;
+    2
+}
+
+/// Read one line, without its newline, a byte at a time. `None` at the end
+/// of input.
+fn read_line(reader: &mut dyn Read) -> Option<io::Result<String>> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) if line.is_empty() => return None,
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Some(Err(e)),
+        }
+    }
+    Some(Ok(String::from_utf8_lossy(&line).into_owned()))
+}
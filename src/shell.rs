@@ -1,9 +1,241 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::ast::{Command, List};
+use crate::jobs::{JobTable, Terminal};
+use crate::parser::{self, ParseError};
+use crate::sys::{self, Pid};
+use crate::vars::{Var, Vars};
+
+/// Non-local control flow requested by a builtin, unwound by the executor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flow {
+    Exit(i32),
+    /// `break n`: leave `n` enclosing loops.
+    Break(usize),
+    /// `continue n`: go on with the next iteration of the `n`th loop.
+    Continue(usize),
+    /// `return n`: leave the running function with status `n`.
+    Return(i32),
+}
+
+/// Options toggled with `shopt -s` / `shopt -u`.
+#[derive(Debug, Clone, Default)]
+pub struct Shopts {
+    /// Patterns that match nothing expand to nothing.
+    pub nullglob: bool,
+    /// Patterns that match nothing are an error and the command is not run.
+    pub failglob: bool,
+    /// Wildcards also match names starting with `.`.
+    pub dotglob: bool,
+    /// Aliases are expanded; on by default in interactive shells.
+    pub expand_aliases: bool,
+}
+
+impl Shopts {
+    pub const NAMES: &[&str] = &["dotglob", "expand_aliases", "failglob", "nullglob"];
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "dotglob" => Some(self.dotglob),
+            "expand_aliases" => Some(self.expand_aliases),
+            "failglob" => Some(self.failglob),
+            "nullglob" => Some(self.nullglob),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "dotglob" => Some(&mut self.dotglob),
+            "expand_aliases" => Some(&mut self.expand_aliases),
+            "failglob" => Some(&mut self.failglob),
+            "nullglob" => Some(&mut self.nullglob),
+            _ => None,
+        }
+    }
+}
+
+/// Options toggled with `set -o name` / `set +o name` or their letters,
+/// as in `set -e`, and on the command line.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// `-e`: exit when a command fails, unless its status is being tested.
+    pub errexit: bool,
+    /// `-C`: `>` does not overwrite existing files; `>|` still does.
+    pub noclobber: bool,
+    /// `-n`: read commands without running them, to check their syntax.
+    pub noexec: bool,
+    /// `-f`: no pathname expansion.
+    pub noglob: bool,
+    /// `-u`: expanding an unset parameter is an error.
+    pub nounset: bool,
+    /// A pipeline's status is that of its last failing command.
+    pub pipefail: bool,
+    /// `-v`: print input lines as they are read.
+    pub verbose: bool,
+    /// `-x`: print commands with `$PS4` before running them.
+    pub xtrace: bool,
+}
+
+impl Options {
+    pub const NAMES: &[&str] = &["errexit", "noclobber", "noexec", "noglob", "nounset", "pipefail", "verbose", "xtrace"];
+
+    /// The options that have a letter, in the order `$-` lists them.
+    pub const LETTERS: &[(char, &str)] = &[
+        ('e', "errexit"),
+        ('f', "noglob"),
+        ('n', "noexec"),
+        ('u', "nounset"),
+        ('v', "verbose"),
+        ('x', "xtrace"),
+        ('C', "noclobber"),
+    ];
+
+    /// The name of the option with letter `c`.
+    pub fn by_letter(c: char) -> Option<&'static str> {
+        Self::LETTERS.iter().find(|&&(l, _)| l == c).map(|&(_, name)| name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "errexit" => Some(self.errexit),
+            "noclobber" => Some(self.noclobber),
+            "noexec" => Some(self.noexec),
+            "noglob" => Some(self.noglob),
+            "nounset" => Some(self.nounset),
+            "pipefail" => Some(self.pipefail),
+            "verbose" => Some(self.verbose),
+            "xtrace" => Some(self.xtrace),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "errexit" => Some(&mut self.errexit),
+            "noclobber" => Some(&mut self.noclobber),
+            "noexec" => Some(&mut self.noexec),
+            "noglob" => Some(&mut self.noglob),
+            "nounset" => Some(&mut self.nounset),
+            "pipefail" => Some(&mut self.pipefail),
+            "verbose" => Some(&mut self.verbose),
+            "xtrace" => Some(&mut self.xtrace),
+            _ => None,
+        }
+    }
+}
+
 pub struct Shell {
     pub last_exit_code: i32,
+    /// `PIPESTATUS`: the status of each command in the last pipeline.
+    pub pipe_status: Vec<i32>,
+    pub flow: Option<Flow>,
+    pub shopts: Shopts,
+    pub options: Options,
+    /// How many conditions are running, such as an `if` test or the left
+    /// side of `&&`, where a failing command does not trigger `set -e`.
+    pub condition_depth: usize,
+    pub jobs: JobTable,
+    /// Pid of the most recent background job, for `$!`.
+    pub last_bg_pid: Option<Pid>,
+    /// Set while the shell does job control on its terminal.
+    pub terminal: Option<Terminal>,
+    /// How many loops are running, for `break` and `continue`.
+    pub loop_depth: usize,
+    /// Functions defined so far, by name.
+    pub functions: HashMap<String, Rc<Command>>,
+    /// Aliases defined with `alias`, by name.
+    pub aliases: HashMap<String, String>,
+    /// `$1`, `$2`, ... of the running function, or of the shell itself.
+    pub positional: Vec<String>,
+    /// For each running function, the variables it declared `local` and
+    /// the values they had before, restored when it returns.
+    pub locals: Vec<Vec<(String, Option<Var>)>>,
+    /// How many files `source` is running, for `return`.
+    pub source_depth: usize,
+    pub vars: Vars,
+    /// `$$`: the pid of the shell itself, also in subshells.
+    pub pid: Pid,
+    /// `$0`: the name of the shell or script.
+    pub arg0: String,
+    /// `$_`: the last argument of the previous command.
+    pub last_arg: String,
+    /// Commands come from the user at a prompt rather than from a script.
+    pub interactive: bool,
+    /// The script or file being run and the line the running command
+    /// starts on, for error messages. `None` at the prompt.
+    pub location: Option<(String, usize)>,
 }
 
 impl Shell {
+    /// `$-`: letters for the options in effect.
+    pub fn option_flags(&self) -> String {
+        let mut flags = String::new();
+        if self.interactive {
+            flags.push('i');
+        }
+        if self.terminal.is_some() {
+            flags.push('m');
+        }
+        for &(letter, name) in Options::LETTERS {
+            if self.options.get(name) == Some(true) {
+                flags.push(letter);
+            }
+        }
+        flags
+    }
+
+    /// `rsh: name: line n: ` while a script or file runs, so that an error
+    /// says where the failing command came from; empty at the prompt.
+    pub fn location(&self) -> String {
+        match &self.location {
+            Some((name, line)) => format!("rsh: {}: line {}: ", name, line),
+            None => String::new(),
+        }
+    }
+
+    /// The start of an error message from the shell itself: the location,
+    /// or just `rsh: ` at the prompt.
+    pub fn error_prefix(&self) -> String {
+        match &self.location {
+            Some(_) => self.location(),
+            None => "rsh: ".to_string(),
+        }
+    }
+
+    /// Parse source, expanding aliases if `expand_aliases` is on.
+    pub fn parse(&self, source: &str) -> Result<List, ParseError> {
+        if self.shopts.expand_aliases {
+            parser::parse_with_aliases(source, &self.aliases)
+        } else {
+            parser::parse(source)
+        }
+    }
+
     pub fn new() -> Self {
-        Shell { last_exit_code: 0 }
+        Shell {
+            last_exit_code: 0,
+            pipe_status: vec![0],
+            flow: None,
+            shopts: Shopts::default(),
+            options: Options::default(),
+            condition_depth: 0,
+            jobs: JobTable::default(),
+            last_bg_pid: None,
+            terminal: None,
+            loop_depth: 0,
+            functions: HashMap::new(),
+            aliases: HashMap::new(),
+            positional: Vec::new(),
+            locals: Vec::new(),
+            source_depth: 0,
+            vars: Vars::from_env(),
+            pid: sys::getpid(),
+            arg0: "rsh".to_string(),
+            last_arg: String::new(),
+            interactive: false,
+            location: None,
+        }
     }
 }
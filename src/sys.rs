@@ -0,0 +1,258 @@
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub type Pid = libc::pid_t;
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 { Err(io::Error::last_os_error()) } else { Ok(ret) }
+}
+
+/// Fork the shell. Returns 0 in the child and the child's pid in the parent.
+pub fn fork() -> io::Result<Pid> {
+    check(unsafe { libc::fork() })
+}
+
+/// Leave a forked child immediately, without running destructors.
+pub fn exit_child(code: i32) -> ! {
+    use std::io::Write;
+    io::stdout().flush().ok();
+    unsafe { libc::_exit(code) }
+}
+
+/// How a child changed state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WaitStatus {
+    /// Exited or was killed by a signal.
+    Exited(ExitStatus),
+    /// Stopped by the given signal.
+    Stopped(i32),
+    Continued,
+}
+
+/// `waitpid` with the given options, retrying on `EINTR`. Returns `None`
+/// if `WNOHANG` was given and no child changed state.
+pub fn wait(pid: Pid, options: libc::c_int) -> io::Result<Option<WaitStatus>> {
+    let mut status = 0;
+    loop {
+        match check(unsafe { libc::waitpid(pid, &mut status, options) }) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(if libc::WIFSTOPPED(status) {
+        WaitStatus::Stopped(libc::WSTOPSIG(status))
+    } else if libc::WIFCONTINUED(status) {
+        WaitStatus::Continued
+    } else {
+        WaitStatus::Exited(ExitStatus::from_raw(status))
+    }))
+}
+
+/// Wait for a child to terminate.
+pub fn wait_pid(pid: Pid) -> io::Result<ExitStatus> {
+    loop {
+        if let Some(WaitStatus::Exited(status)) = wait(pid, 0)? {
+            return Ok(status);
+        }
+    }
+}
+
+/// The shell's numeric exit status for a child's wait status: its exit
+/// code, or 128 plus the number of the signal that killed it.
+pub fn status_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(sig)) => 128 + sig,
+        (None, None) => 0,
+    }
+}
+
+/// How a process killed by `sig` is described: "Terminated", "Killed", ...
+pub fn signal_description(sig: i32) -> String {
+    let text = unsafe { libc::strsignal(sig) };
+    if text.is_null() {
+        return format!("Signal {}", sig);
+    }
+    unsafe { std::ffi::CStr::from_ptr(text) }.to_string_lossy().into_owned()
+}
+
+/// Put `pid` in process group `pgid` (0 means its own pid).
+pub fn setpgid(pid: Pid, pgid: Pid) -> io::Result<()> {
+    check(unsafe { libc::setpgid(pid, pgid) }).map(|_| ())
+}
+
+pub fn getpid() -> Pid {
+    unsafe { libc::getpid() }
+}
+
+pub fn kill(pid: Pid, sig: i32) -> io::Result<()> {
+    check(unsafe { libc::kill(pid, sig) }).map(|_| ())
+}
+
+pub fn isatty(fd: i32) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+/// Make `pgid` the foreground process group of the terminal `fd`.
+pub fn tcsetpgrp(fd: i32, pgid: Pid) -> io::Result<()> {
+    check(unsafe { libc::tcsetpgrp(fd, pgid) }).map(|_| ())
+}
+
+pub fn tcgetattr(fd: i32) -> io::Result<libc::termios> {
+    let mut modes = unsafe { std::mem::zeroed() };
+    check(unsafe { libc::tcgetattr(fd, &mut modes) })?;
+    Ok(modes)
+}
+
+pub fn tcsetattr(fd: i32, modes: &libc::termios) -> io::Result<()> {
+    check(unsafe { libc::tcsetattr(fd, libc::TCSADRAIN, modes) }).map(|_| ())
+}
+
+/// Signals an interactive shell ignores so that only its jobs react to
+/// the terminal's job-control keys.
+const JOB_SIGNALS: [libc::c_int; 4] = [libc::SIGQUIT, libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU];
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Take over the terminal for job control: wait until the shell is in the
+/// foreground, move it into its own process group and ignore the job
+/// control signals. Ctrl-C only sets a flag, see [`interrupted`].
+pub fn claim_terminal(fd: i32) -> io::Result<Pid> {
+    loop {
+        let pgrp = unsafe { libc::getpgrp() };
+        if unsafe { libc::tcgetpgrp(fd) } == pgrp {
+            break;
+        }
+        kill(-pgrp, libc::SIGTTIN)?;
+    }
+    unsafe {
+        for sig in JOB_SIGNALS {
+            libc::signal(sig, libc::SIG_IGN);
+        }
+        libc::signal(libc::SIGINT, on_sigint as *const () as libc::sighandler_t);
+    }
+    let pid = getpid();
+    // Fails harmlessly if the shell already leads its session.
+    setpgid(0, 0).ok();
+    tcsetpgrp(fd, pid)?;
+    Ok(pid)
+}
+
+/// Whether Ctrl-C was pressed while the shell itself was running commands.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn set_interrupted(value: bool) {
+    INTERRUPTED.store(value, Ordering::SeqCst);
+}
+
+/// Run in a new child before it execs or starts running a job: join
+/// process group `pgid` (0 for a new one), take the terminal if `tty` is
+/// given, and restore default signal handling.
+///
+/// Only async-signal-safe calls are made, so this is usable from `pre_exec`.
+pub fn child_setup(pgid: Option<Pid>, tty: Option<i32>) {
+    unsafe {
+        if let Some(pgid) = pgid {
+            libc::setpgid(0, pgid);
+            if let Some(fd) = tty {
+                libc::tcsetpgrp(fd, libc::getpgrp());
+            }
+        }
+        for sig in JOB_SIGNALS.into_iter().chain([libc::SIGINT, libc::SIGCHLD]) {
+            libc::signal(sig, libc::SIG_DFL);
+        }
+    }
+}
+
+/// Ignore Ctrl-C and Ctrl-\ from the terminal, as a background job does
+/// when it shares the shell's process group. Programs it runs inherit this.
+pub fn ignore_interrupts() {
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+        libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+    }
+}
+
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP), ("INT", libc::SIGINT), ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL), ("TRAP", libc::SIGTRAP), ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS), ("FPE", libc::SIGFPE), ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1), ("SEGV", libc::SIGSEGV), ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE), ("ALRM", libc::SIGALRM), ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD), ("CONT", libc::SIGCONT), ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP), ("TTIN", libc::SIGTTIN), ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG), ("XCPU", libc::SIGXCPU), ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM), ("PROF", libc::SIGPROF), ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO), ("SYS", libc::SIGSYS),
+];
+
+/// Look up a signal by number or by name, with or without `SIG`.
+pub fn signal_number(name: &str) -> Option<i32> {
+    if let Ok(n) = name.parse::<i32>() {
+        return (0..65).contains(&n).then_some(n);
+    }
+    let upper = name.to_ascii_uppercase();
+    let upper = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNALS.iter().find(|(n, _)| *n == upper).map(|&(_, sig)| sig)
+}
+
+pub fn signal_names() -> impl Iterator<Item = (&'static str, i32)> {
+    SIGNALS.iter().copied()
+}
+
+/// Create a pipe, returning `(read, write)` ends.
+pub fn pipe() -> io::Result<(i32, i32)> {
+    let mut fds = [0; 2];
+    check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    for fd in fds {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    Ok((fds[0], fds[1]))
+}
+
+pub fn dup(fd: i32) -> io::Result<i32> {
+    check(unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 10) })
+}
+
+/// Make `dst` a copy of `src` that is kept across `exec`.
+pub fn dup2(src: i32, dst: i32) -> io::Result<()> {
+    if src == dst {
+        // dup2 would leave close-on-exec set.
+        return check(unsafe { libc::fcntl(dst, libc::F_SETFD, 0) }).map(|_| ());
+    }
+    check(unsafe { libc::dup2(src, dst) }).map(|_| ())
+}
+
+/// Reads straight from a descriptor, without buffering, so that nothing
+/// past what a builtin asked for is taken from input it shares with the
+/// commands after it.
+pub struct FdReader(pub i32);
+
+impl io::Read for FdReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = unsafe { libc::read(self.0, buf.as_mut_ptr().cast(), buf.len()) };
+            if n >= 0 {
+                return Ok(n as usize);
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+pub fn close(fd: i32) {
+    unsafe { libc::close(fd) };
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::env;
+
+use crate::arith;
+
+/// A shell variable and its attributes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Var {
+    /// `None` for a variable that only has attributes, as after `export
+    /// name`; it stays unset until it is assigned.
+    pub value: Option<String>,
+    /// Passed to the environment of child processes.
+    pub exported: bool,
+    /// Can be neither assigned nor unset.
+    pub readonly: bool,
+    /// Assigned values are evaluated as arithmetic expressions.
+    pub integer: bool,
+}
+
+/// The shell's variables. Child processes get the exported ones as their
+/// environment; the shell's own process environment is left alone.
+#[derive(Debug, Clone, Default)]
+pub struct Vars {
+    vars: HashMap<String, Var>,
+}
+
+impl Vars {
+    /// Variables for the environment the shell was started with, all
+    /// exported.
+    pub fn from_env() -> Self {
+        let vars = env::vars()
+            .map(|(name, value)| (name, Var { value: Some(value), exported: true, ..Var::default() }))
+            .collect();
+        Vars { vars }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).and_then(|v| v.value.as_deref())
+    }
+
+    pub fn var(&self, name: &str) -> Option<&Var> {
+        self.vars.get(name)
+    }
+
+    /// Assign a value, keeping the variable's attributes.
+    pub fn set(&mut self, name: &str, value: impl Into<String>) -> Result<(), String> {
+        let mut value = value.into();
+        if let Some(var) = self.vars.get(name) {
+            if var.readonly {
+                return Err(format!("{}: readonly variable", name));
+            }
+            if var.integer {
+                value = arith::eval(&value, self)?.to_string();
+            }
+        }
+        self.vars.entry(name.to_string()).or_default().value = Some(value);
+        Ok(())
+    }
+
+    pub fn unset(&mut self, name: &str) -> Result<(), String> {
+        if self.vars.get(name).is_some_and(|v| v.readonly) {
+            return Err(format!("{}: cannot unset: readonly variable", name));
+        }
+        self.vars.remove(name);
+        Ok(())
+    }
+
+    /// The variable, created without a value if it does not exist yet, for
+    /// changing its attributes.
+    pub fn entry(&mut self, name: &str) -> &mut Var {
+        self.vars.entry(name.to_string()).or_default()
+    }
+
+    /// Put back a variable as it was saved with `var`, attributes and all.
+    pub fn restore(&mut self, name: &str, var: Option<Var>) {
+        match var {
+            Some(var) => self.vars.insert(name.to_string(), var),
+            None => self.vars.remove(name),
+        };
+    }
+
+    /// All variables, sorted by name.
+    pub fn sorted(&self) -> Vec<(&str, &Var)> {
+        let mut all: Vec<_> = self.vars.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        all.sort_by_key(|&(name, _)| name);
+        all
+    }
+
+    /// The environment for a child process.
+    pub fn environment(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars
+            .iter()
+            .filter(|(_, v)| v.exported)
+            .filter_map(|(k, v)| Some((k.as_str(), v.value.as_deref()?)))
+    }
+}
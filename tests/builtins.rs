@@ -0,0 +1,11 @@
+mod common;
+
+use common::rsh;
+
+#[test]
+fn exit_status() {
+    assert_eq!(rsh("(false; exit); echo $?\n"), "1\n");
+    assert_eq!(rsh("(exit 3); echo $?\n"), "3\n");
+    assert_eq!(rsh("(exit 257); echo $?\n"), "1\n");
+    assert_eq!(rsh("(exit foo); echo $?\n"), "2\n");
+}
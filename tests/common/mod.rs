@@ -0,0 +1,26 @@
+// Each test file uses only some of these.
+#![allow(dead_code)]
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Run rsh with `args` and `input` as its standard input.
+pub fn run(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rsh"))
+        .args(args)
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("rsh should start");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Run `script` as rsh's standard input, the way a script piped to the
+/// shell runs, and return what it writes to standard output.
+pub fn rsh(script: &str) -> String {
+    String::from_utf8_lossy(&run(&[], script).stdout).into_owned()
+}
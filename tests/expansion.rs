@@ -0,0 +1,17 @@
+mod common;
+
+use common::rsh;
+
+#[test]
+fn default_assignment_in_a_pipeline_stays_in_the_stage() {
+    assert_eq!(rsh("${y:=5} | cat; echo \"[${y-unset}]\"\n"), "[unset]\n");
+    assert_eq!(rsh("echo ${y:=5} | cat; echo \"[${y-unset}]\"\n"), "5\n[unset]\n");
+    assert_eq!(rsh("echo ${y:=5}; echo \"[$y]\"\n"), "5\n[5]\n");
+}
+
+#[test]
+fn arithmetic_in_a_pipeline_stays_in_the_stage() {
+    assert_eq!(rsh("x=1; echo $((x++)) | cat; echo $x\n"), "1\n1\n");
+    assert_eq!(rsh("x=1; true | echo $((x = 7)); echo $x\n"), "7\n1\n");
+    assert_eq!(rsh("x=1; echo $((x += 2)); echo $x\n"), "3\n3\n");
+}
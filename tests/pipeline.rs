@@ -0,0 +1,24 @@
+mod common;
+
+use common::rsh;
+
+#[test]
+fn stage_expansions_read_the_pipe() {
+    let script = "echo hi | echo \"got: $(cat)\"\necho next\n";
+    assert_eq!(rsh(script), "got: hi\nnext\n");
+}
+
+#[test]
+fn stage_assignments_stay_in_the_stage() {
+    assert_eq!(rsh("${y:=5} | cat; echo \"[$y]\"\n"), "[]\n");
+    assert_eq!(rsh("x=1; echo $((x++)) | cat; echo $x\n"), "1\n1\n");
+    assert_eq!(rsh("x=1; echo | echo $((x++)); echo $x\n"), "1\n1\n");
+}
+
+#[test]
+fn stages_run_builtins_functions_and_programs() {
+    assert_eq!(rsh("echo a b | tr a-z A-Z | { read l; echo \"<$l>\"; }\n"), "<A B>\n");
+    assert_eq!(rsh("f() { echo \"f $1 $(cat)\"; }; echo in | f arg\n"), "f arg in\n");
+    assert_eq!(rsh("false | true; echo ${PIPESTATUS[@]} $?\n"), "1 0 0\n");
+    assert_eq!(rsh("echo x | nosuchcommand; echo $?\n"), "127\n");
+}
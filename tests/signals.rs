@@ -0,0 +1,16 @@
+mod common;
+
+use common::rsh;
+
+#[test]
+fn script_continues_after_a_child_dies_of_sigint() {
+    assert_eq!(rsh("sh -c 'kill -INT $$'; echo after $?\necho next\n"), "after 130\nnext\n");
+}
+
+#[test]
+fn background_jobs_share_the_group_without_job_control() {
+    let script = "sleep 5 &\n\
+        [ $(ps -o pgid= -p $!) -eq $(ps -o pgid= -p $$) ] && echo same\n\
+        kill %1; wait $!; echo $?\n";
+    assert_eq!(rsh(script), "same\n143\n");
+}
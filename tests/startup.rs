@@ -0,0 +1,18 @@
+mod common;
+
+use std::fs;
+
+#[test]
+fn rc_file_errors_give_the_line_and_do_not_stop_it() {
+    let rc = std::env::temp_dir().join(format!("rsh-startup-{}", std::process::id()));
+    fs::write(&rc, "echo one\nnosuchcmd\nif then fi\ncd /nonexistent\necho four\n").unwrap();
+    let output = common::run(&["--rcfile", rc.to_str().unwrap(), "-i"], "");
+    fs::remove_file(&rc).ok();
+
+    let name = rc.display();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&format!("rsh: {}: line 2: nosuchcmd: command not found", name)), "{}", stderr);
+    assert!(stderr.contains(&format!("rsh: {}: line 3, column 4: syntax error", name)), "{}", stderr);
+    assert!(stderr.contains(&format!("rsh: {}: line 4: cd: /nonexistent:", name)), "{}", stderr);
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("one\nfour\n"));
+}
@@ -0,0 +1,12 @@
+mod common;
+
+use common::rsh;
+
+#[test]
+fn attributes_on_an_unset_name_leave_it_unset() {
+    assert_eq!(rsh("export FOO; echo \"[${FOO-unset}]\"; env | grep -c '^FOO='\n"), "[unset]\n0\n");
+    assert_eq!(rsh("export FOO; FOO=bar; env | grep '^FOO='\n"), "FOO=bar\n");
+    assert_eq!(rsh("readonly X; echo \"[${X-unset}]\"\n"), "[unset]\n");
+    assert_eq!(rsh("declare -i N; echo \"[${N-unset}]\"; N=2+3; echo $N\n"), "[unset]\n5\n");
+    assert_eq!(rsh("export Z; declare -p Z\n"), "declare -x Z\n");
+}